@@ -0,0 +1,66 @@
+//! 1000x600的大图上基线版本的`find_top_n`和现在的`find_peaks`的耗时对比
+//!
+//! `cargo bench --bench find_top_n`, 不依赖额外的基准测试框架, 输出每轮耗时的最小值和中位数
+
+mod baseline;
+
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, GenericImage, Rgba};
+use image_magic::{find_peaks, HilltopParamAndResult};
+
+const WIDTH: u32 = 1000;
+const HEIGHT: u32 = 600;
+const CH_SIZE: u32 = 60;
+const ROUNDS: usize = 10;
+
+/// 渐变背景, 挑战图在三个位置各贴一个50x50的白块
+fn make_images() -> (DynamicImage, DynamicImage) {
+    let mut bg_image = DynamicImage::new_rgba8(WIDTH, HEIGHT);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            bg_image.put_pixel(x, y, Rgba([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8, 255]));
+        }
+    }
+    let mut cg_image = bg_image.clone();
+    for (cx, cy) in [(200u32, 150u32), (700, 400), (450, 300)] {
+        for y in cy - 25..cy + 25 {
+            for x in cx - 25..cx + 25 {
+                cg_image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+            }
+        }
+    }
+    (bg_image, cg_image)
+}
+
+/// 先跑一轮不计时, 再跑`ROUNDS`轮, 返回排好序的耗时
+fn measure<F: FnMut()>(mut run: F) -> Vec<Duration> {
+    run();
+    let mut elapsed: Vec<Duration> = (0..ROUNDS).map(|_| {
+        let start = Instant::now();
+        run();
+        start.elapsed()
+    }).collect();
+    elapsed.sort();
+    elapsed
+}
+
+fn main() {
+    let (bg_image, cg_image) = make_images();
+    let points = find_peaks(HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), CH_SIZE, 3)).unwrap();
+    let baseline_points = baseline::find_top_n(baseline::HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), CH_SIZE, 3));
+    // 两个版本找到的点必须完全一样, 不然比较耗时没有意义
+    assert_eq!(points.iter().map(|p| (p.x, p.y, p.weight)).collect::<Vec<_>>(),
+               baseline_points.iter().map(|p| (p.x, p.y, p.weight)).collect::<Vec<_>>());
+
+    let baseline = measure(|| {
+        baseline::find_top_n(baseline::HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), CH_SIZE, 3));
+    });
+    let current = measure(|| {
+        find_peaks(HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), CH_SIZE, 3)).unwrap();
+    });
+    println!("find_top_n {}x{} ch_size={} top 3, {} rounds:", WIDTH, HEIGHT, CH_SIZE, ROUNDS);
+    println!("  baseline: min {:?}, median {:?}", baseline[0], baseline[ROUNDS / 2]);
+    println!("  current:  min {:?}, median {:?}", current[0], current[ROUNDS / 2]);
+    println!("  speedup (median): {:.1}x", baseline[ROUNDS / 2].as_secs_f64() / current[ROUNDS / 2].as_secs_f64());
+}
@@ -1,4 +1,5 @@
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "python")]
     pyo3_build_config::add_extension_module_link_args();
-}
\ No newline at end of file
+}
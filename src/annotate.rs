@@ -0,0 +1,186 @@
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use image::imageops::FilterType;
+use imageproc::drawing::{draw_filled_circle_mut, draw_filled_rect_mut, draw_hollow_circle_mut, draw_hollow_rect_mut};
+use imageproc::rect::Rect;
+
+use crate::error::{Error, Result};
+use crate::image_hill_top_v2::Point;
+use crate::image_utils::rgb_diff;
+
+/// 3x5的点阵数字, 每行低3位从左到右
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+/// 点阵放大倍数
+const FONT_SCALE: i32 = 2;
+/// 每个结果一种颜色, 名次超过颜色数的循环使用
+const PALETTE: [[u8; 3]; 6] = [[230, 25, 75], [60, 180, 75], [0, 130, 200], [245, 130, 48], [145, 30, 180], [70, 240, 240]];
+/// 差值叠加层最不透明的时候的alpha
+const OVERLAY_ALPHA: f32 = 0.6;
+
+/// 标签文字占的宽和高
+fn text_size(text: &str) -> (i32, i32) {
+    let count = text.chars().filter(|c| c.is_ascii_digit()).count() as i32;
+    ((count * 4 - 1).max(0) * FONT_SCALE, 5 * FONT_SCALE)
+}
+
+/// 用点阵字体画数字, 非数字的字符跳过
+fn draw_text(img: &mut RgbaImage, x: i32, y: i32, text: &str, color: Rgba<u8>) {
+    for (i, digit) in text.chars().filter_map(|c| c.to_digit(10)).enumerate() {
+        let left = x + i as i32 * 4 * FONT_SCALE;
+        for (row, bits) in DIGITS[digit as usize].iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    let rect = Rect::at(left + col * FONT_SCALE, y + row as i32 * FONT_SCALE).of_size(FONT_SCALE as u32, FONT_SCALE as u32);
+                    draw_filled_rect_mut(img, rect, color);
+                }
+            }
+        }
+    }
+}
+
+/// 带底色的标签, 超出图片的话往里挪
+fn draw_label(img: &mut RgbaImage, x: i32, y: i32, text: &str, color: Rgba<u8>, background: Rgba<u8>) {
+    let (text_width, text_height) = text_size(text);
+    let (width, height) = (text_width + 4, text_height + 4);
+    let x = x.clamp(0, (img.width() as i32 - width).max(0));
+    let y = y.clamp(0, (img.height() as i32 - height).max(0));
+    draw_filled_rect_mut(img, Rect::at(x, y).of_size(width as u32, height as u32), background);
+    draw_text(img, x + 2, y + 2, text, color);
+}
+
+/// 挑战图和底图的差值画成半透明的红色叠加层, 差值越大越红
+fn overlay_diff(img: &mut RgbaImage, challenge: &DynamicImage, background: &DynamicImage) {
+    let (width, height) = challenge.dimensions();
+    let background = if background.dimensions() == (width, height) {
+        background.clone()
+    } else {
+        background.resize_exact(width, height, FilterType::Triangle)
+    };
+    let diff: Vec<i32> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| rgb_diff(challenge.get_pixel(x, y), background.get_pixel(x, y)))
+        .collect();
+    let max_diff = diff.iter().copied().max().unwrap_or(0);
+    if max_diff == 0 {
+        return;
+    }
+    for (pixel, &value) in img.pixels_mut().zip(&diff) {
+        let alpha = OVERLAY_ALPHA * value as f32 / max_diff as f32;
+        for (c, target) in [255.0, 0.0, 0.0].iter().enumerate() {
+            pixel[c] = (pixel[c] as f32 * (1.0 - alpha) + target * alpha).round() as u8;
+        }
+    }
+}
+
+/// 把识别结果画在挑战图上, 人工检查用。
+///
+/// 每个结果画`bbox`框、中心的圆点和名次, 框的上方标出权重;
+/// 传了`background`的话先把挑战图和底图的差值画成半透明的叠加层。
+/// 点或者`bbox`超出挑战图、`bbox`左右上下颠倒的话返回`Error::InvalidParameter`
+pub fn annotate(challenge: &DynamicImage, points: &[Point], background: Option<&DynamicImage>) -> Result<RgbaImage> {
+    let (width, height) = challenge.dimensions();
+    for point in points {
+        let (left, top, right, bottom) = point.bbox;
+        if left > right || top > bottom || right >= width as usize || bottom >= height as usize
+            || point.x >= width as usize || point.y >= height as usize {
+            return Err(Error::InvalidParameter(format!("point ({}, {}) with bbox {:?} does not fit the {}x{} image",
+                                                       point.x, point.y, point.bbox, width, height)));
+        }
+    }
+    if background.is_some_and(|bg| bg.width() == 0 || bg.height() == 0) {
+        return Err(Error::EmptyInput("background image has no pixels".to_string()));
+    }
+    let mut img = challenge.to_rgba8();
+    if let Some(background) = background {
+        overlay_diff(&mut img, challenge, background);
+    }
+    let white = Rgba([255, 255, 255, 255]);
+    for (i, point) in points.iter().enumerate() {
+        let [r, g, b] = PALETTE[i % PALETTE.len()];
+        let color = Rgba([r, g, b, 255]);
+        let (left, top, right, bottom) = point.bbox;
+        let rect = Rect::at(left as i32, top as i32).of_size((right - left + 1) as u32, (bottom - top + 1) as u32);
+        draw_hollow_rect_mut(&mut img, rect, color);
+
+        let center = (point.x as i32, point.y as i32);
+        draw_hollow_circle_mut(&mut img, center, 7, white);
+        draw_filled_circle_mut(&mut img, center, 6, color);
+        let rank = if point.rank > 0 { point.rank } else { i + 1 }.to_string();
+        let (text_width, text_height) = text_size(&rank);
+        draw_text(&mut img, center.0 - text_width / 2, center.1 - text_height / 2, &rank, white);
+
+        // 权重标在框的上方, 放不下的话标在框里面
+        let weight = point.weight.to_string();
+        let label_height = text_size(&weight).1 + 4;
+        let label_y = if top as i32 >= label_height { top as i32 - label_height } else { top as i32 + 1 };
+        draw_label(&mut img, left as i32, label_y, &weight, white, color);
+    }
+    Ok(img)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::annotate::{annotate, draw_text, text_size};
+    use crate::error::Error;
+    use crate::image_hill_top_v2::{find_top_n, HilltopParamAndResult, Point};
+    use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+    #[test]
+    fn test_draw_text() {
+        let mut img = RgbaImage::from_pixel(30, 12, Rgba([0, 0, 0, 255]));
+        draw_text(&mut img, 1, 1, "10", Rgba([255, 255, 255, 255]));
+        assert_eq!(text_size("10"), (14, 10));
+        // "1"的第一行只有中间一列, "0"的第一行三列都有
+        assert_eq!(img.get_pixel(1, 1)[0], 0);
+        assert_eq!(img.get_pixel(3, 1)[0], 255);
+        assert_eq!((9..15).filter(|&x| img.get_pixel(x, 1)[0] == 255).count(), 6);
+        // 画到图片外面不会出错
+        draw_text(&mut img, 25, 8, "999", Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn test_annotate() {
+        let bg_image = image::open("./src/images/1.jpg").unwrap();
+        let cg_image = image::open("./src/images/0.jpg").unwrap();
+        let points = find_top_n(HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 40, 2)).unwrap();
+
+        let plain = annotate(&cg_image, &points, None).unwrap();
+        assert_eq!(plain.dimensions(), cg_image.dimensions());
+        // 框的边上是第一个结果的颜色
+        let (left, top, _, bottom) = points[0].bbox;
+        assert_eq!(*plain.get_pixel(left as u32, (top + bottom) as u32 / 2), Rgba([230, 25, 75, 255]));
+        // 中心的圆点上写着名次
+        let center = plain.get_pixel(points[0].x as u32, points[0].y as u32);
+        assert!(*center == Rgba([230, 25, 75, 255]) || *center == Rgba([255, 255, 255, 255]));
+
+        // 差值叠加层只改变有差异的地方
+        let overlay = annotate(&cg_image, &points, Some(&bg_image)).unwrap();
+        let same = DynamicImage::ImageRgba8(annotate(&cg_image, &[], Some(&cg_image)).unwrap());
+        assert_eq!(same.to_rgba8(), cg_image.to_rgba8());
+        assert_ne!(overlay, plain);
+    }
+
+    #[test]
+    fn test_annotate_invalid_bbox() {
+        let cg_image = image::open("./src/images/0.jpg").unwrap();
+        for bbox in [(50, 20, 40, 60), (40, 60, 50, 20), (280, 20, 300, 60), (0, 0, usize::MAX, 10)] {
+            let points = [Point::new(45, 40, 100, 0.5, bbox, 1)];
+            let err = annotate(&cg_image, &points, None).unwrap_err();
+            assert!(matches!(err, Error::InvalidParameter(_)), "{:?}", bbox);
+        }
+        let points = [Point::new(300, 40, 100, 0.5, (280, 20, 299, 60), 1)];
+        assert!(matches!(annotate(&cg_image, &points, None), Err(Error::InvalidParameter(_))));
+        let empty = DynamicImage::new_rgba8(0, 0);
+        assert!(matches!(annotate(&cg_image, &[], Some(&empty)), Err(Error::EmptyInput(_))));
+    }
+}
@@ -0,0 +1,453 @@
+use std::path::Path;
+
+use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+use image::imageops::FilterType;
+#[cfg(feature = "python")]
+use pyo3::{prelude::*, PyObjectProtocol};
+
+use crate::error::{Error, Result};
+use crate::image_avg_merger::RGBA;
+#[cfg(feature = "python")]
+use crate::{image_utils::encode_png_b64, input::ImageSource};
+
+/// 最后一个字节是格式版本, 像素统计之后是预热阶段暂存的样本
+const MAGIC: &[u8; 5] = b"IMACC";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + 4 + 8 + 8 + 8;
+
+/// 标准差的下限, 避免几张几乎一样的图把标准差压成0之后所有新样本都被拒绝
+const MIN_STD: f64 = 4.0;
+
+/// 增量合成背景图, 图片可以一张一张地喂进来, 不需要全部留在内存里
+///
+/// 每个像素点维护两组累加值: 所有样本的和/平方和, 以及落在当前均值`sigma`倍标准差以内的样本的和。
+/// 前`warmup`张图片没有足够的统计量, 先暂存在内存里, 凑够之后每张和其他预热样本比较(留一法)筛一遍,
+/// 这样预热样本里带滑块的区域也不会混进背景。输出时优先使用被接受的样本的均值。
+#[cfg_attr(feature = "python", pyclass(module = "image_magic"))]
+#[derive(Clone, Debug)]
+pub struct BackgroundAccumulator {
+    width: u32,
+    height: u32,
+    sigma: f64,
+    warmup: u64,
+    count: u64,
+    total: Vec<RGBA>,
+    clipped: Vec<RGBA>,
+    /// 预热阶段的样本, 按像素下标排列, 预热结束之后清空
+    pending: Vec<Vec<Rgba<u8>>>,
+}
+
+fn valid_sigma(sigma: f64) -> bool {
+    sigma.is_finite() && sigma > 0.0
+}
+
+impl BackgroundAccumulator {
+    pub fn new(sigma: f64, warmup: u64) -> Result<BackgroundAccumulator> {
+        if !valid_sigma(sigma) {
+            return Err(Error::InvalidParameter(format!("sigma must be a finite number greater than 0, got {}", sigma)));
+        }
+        Ok(BackgroundAccumulator {
+            width: 0,
+            height: 0,
+            sigma,
+            warmup,
+            count: 0,
+            total: vec![],
+            clipped: vec![],
+            pending: vec![],
+        })
+    }
+
+    fn pixel_count(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// 第一张图片决定背景的尺寸, 后面尺寸不一致的图片会被缩放
+    pub fn add(&mut self, img: &DynamicImage) -> Result<()> {
+        if img.width() == 0 || img.height() == 0 {
+            return Err(Error::EmptyInput("image has no pixels".to_string()));
+        }
+        if self.count == 0 {
+            self.width = img.width();
+            self.height = img.height();
+            let size = self.pixel_count();
+            self.total = vec![RGBA::new(); size];
+            self.clipped = vec![RGBA::new(); size];
+        }
+        let resized;
+        let img = if img.dimensions() != (self.width, self.height) {
+            resized = img.resize_exact(self.width, self.height, FilterType::Triangle);
+            &resized
+        } else {
+            img
+        };
+
+        let warming_up = self.count < self.warmup;
+        if warming_up {
+            self.pending.push(img.pixels().map(|(_, _, val)| val).collect());
+        }
+        for (x, y, val) in img.pixels() {
+            let idx = y as usize * self.width as usize + x as usize;
+            let total = &mut self.total[idx];
+            let accept = !warming_up && total.mean_std().iter().take(3).enumerate().all(|(c, (mean, std))| {
+                (val[c] as f64 - mean).abs() <= self.sigma * std.max(MIN_STD)
+            });
+            if accept {
+                self.clipped[idx].set_val(val);
+            }
+            total.set_val(val);
+        }
+        self.count += 1;
+        if warming_up && self.count == self.warmup {
+            self.flush_warmup();
+        }
+        Ok(())
+    }
+
+    /// 预热结束, 每个暂存的样本和其他预热样本的均值/标准差比较, 只有一张的时候直接接受
+    fn flush_warmup(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        let mut others = Vec::with_capacity(pending.len());
+        for idx in 0..self.pixel_count() {
+            for (k, sample) in pending.iter().enumerate() {
+                let val = sample[idx];
+                let accept = (0..3).all(|c| {
+                    others.clear();
+                    others.extend(pending.iter().enumerate().filter(|(j, _)| *j != k).map(|(_, other)| other[idx][c] as f64));
+                    if others.is_empty() {
+                        return true;
+                    }
+                    let n = others.len() as f64;
+                    let mean = others.iter().sum::<f64>() / n;
+                    let std = (others.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+                    (val[c] as f64 - mean).abs() <= self.sigma * std.max(MIN_STD)
+                });
+                if accept {
+                    self.clipped[idx].set_val(val);
+                }
+            }
+        }
+    }
+
+    /// 当前估计出来的背景图
+    pub fn background(&self) -> Result<DynamicImage> {
+        if self.count == 0 {
+            return Err(Error::EmptyInput("no image has been added".to_string()));
+        }
+        let mut output = DynamicImage::new_rgba8(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let idx = y as usize * self.width as usize + x as usize;
+                let clipped = &self.clipped[idx];
+                let val = if clipped.total_record() > 0 {
+                    clipped.avg_rgb()
+                } else {
+                    self.total[idx].avg_rgb()
+                };
+                output.put_pixel(x, y, val);
+            }
+        }
+        Ok(output)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.total.len() * 2 * RGBA::ENCODED_LEN
+            + 4 + self.pending.len() * self.pixel_count() * 4);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&self.width.to_le_bytes());
+        out.extend_from_slice(&self.height.to_le_bytes());
+        out.extend_from_slice(&self.sigma.to_le_bytes());
+        out.extend_from_slice(&self.warmup.to_le_bytes());
+        out.extend_from_slice(&self.count.to_le_bytes());
+        for (total, clipped) in self.total.iter().zip(&self.clipped) {
+            total.encode(&mut out);
+            clipped.encode(&mut out);
+        }
+        out.extend_from_slice(&(self.pending.len() as u32).to_le_bytes());
+        for sample in &self.pending {
+            for val in sample {
+                out.extend_from_slice(&val.0);
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<BackgroundAccumulator> {
+        let corrupted = |msg: &str| Error::Corrupted(msg.to_string());
+        if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+            return Err(corrupted("not a background accumulator file"));
+        }
+        let version = data[MAGIC.len()];
+        if version != VERSION {
+            return Err(Error::Corrupted(format!("unsupported background accumulator version {}", version)));
+        }
+        let header = &data[MAGIC.len() + 1..HEADER_LEN];
+        let width = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let height = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let sigma = f64::from_le_bytes(header[8..16].try_into().unwrap());
+        let warmup = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let count = u64::from_le_bytes(header[24..32].try_into().unwrap());
+        if !valid_sigma(sigma) {
+            return Err(Error::Corrupted(format!("invalid sigma {}", sigma)));
+        }
+        let size = width as usize * height as usize;
+        if (count == 0) != (size == 0) {
+            return Err(corrupted("image size does not match sample count"));
+        }
+
+        let pixels_len = size.checked_mul(2 * RGBA::ENCODED_LEN).ok_or_else(|| corrupted("image size overflow"))?;
+        let body = &data[HEADER_LEN..];
+        if body.len() < pixels_len {
+            return Err(Error::Corrupted(format!("expected {} pixels, got {} bytes", size, body.len())));
+        }
+        let (pixels, rest) = body.split_at(pixels_len);
+        let mut total = Vec::with_capacity(size);
+        let mut clipped = Vec::with_capacity(size);
+        for chunk in pixels.chunks_exact(2 * RGBA::ENCODED_LEN) {
+            let pixel_total = RGBA::decode(&chunk[..RGBA::ENCODED_LEN]);
+            let pixel_clipped = RGBA::decode(&chunk[RGBA::ENCODED_LEN..]);
+            // 每个样本都计入`total`, 被接受的样本是其中一部分; 不检查的话`background()`会除以0
+            if pixel_total.total_record() != count || pixel_clipped.total_record() > count {
+                return Err(corrupted("pixel sample count does not match"));
+            }
+            if !pixel_total.in_range() || !pixel_clipped.in_range() {
+                return Err(corrupted("pixel sums out of range"));
+            }
+            total.push(pixel_total);
+            clipped.push(pixel_clipped);
+        }
+
+        if rest.len() < 4 {
+            return Err(corrupted("missing warmup samples"));
+        }
+        let (pending_count, rest) = rest.split_at(4);
+        let pending_count = u32::from_le_bytes(pending_count.try_into().unwrap()) as u64;
+        if pending_count != if count < warmup { count } else { 0 } {
+            return Err(corrupted("warmup sample count does not match"));
+        }
+        let pending_len = (pending_count as usize).checked_mul(size).and_then(|n| n.checked_mul(4))
+            .ok_or_else(|| corrupted("warmup samples overflow"))?;
+        if rest.len() != pending_len {
+            return Err(Error::Corrupted(format!("expected {} bytes of warmup samples, got {}", pending_len, rest.len())));
+        }
+        let pending = if size > 0 {
+            rest.chunks_exact(size * 4)
+                .map(|sample| sample.chunks_exact(4).map(|c| Rgba([c[0], c[1], c[2], c[3]])).collect())
+                .collect()
+        } else {
+            vec![]
+        };
+        Ok(BackgroundAccumulator { width, height, sigma, warmup, count, total, clipped, pending })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<BackgroundAccumulator> {
+        BackgroundAccumulator::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl BackgroundAccumulator {
+    /// `sigma`: 离均值超过几倍标准差的样本不参与合成; `warmup`: 前几张图片先暂存, 凑够之后互相比较筛掉异常区域
+    #[new]
+    #[args(sigma = "2.5", warmup = "3")]
+    fn py_new(sigma: f64, warmup: u64) -> PyResult<BackgroundAccumulator> {
+        Ok(BackgroundAccumulator::new(sigma, warmup)?)
+    }
+
+    /// 输入格式和`avg_b64`一样
+    #[pyo3(name = "add")]
+    fn py_add(&mut self, py: Python, image: ImageSource) -> PyResult<()> {
+        Ok(py.allow_threads(|| image.decode().and_then(|img| self.add(&img)))?)
+    }
+
+    fn extend(&mut self, py: Python, images: Vec<ImageSource>) -> PyResult<()> {
+        Ok(py.allow_threads(|| {
+            images.into_iter().try_for_each(|image| self.add(&image.decode()?))
+        })?)
+    }
+
+    #[getter(count)]
+    fn py_count(&self) -> u64 {
+        self.count
+    }
+
+    /// (width, height), 还没有图片的时候是(0, 0)
+    #[getter]
+    fn size(&self) -> (u32, u32) {
+        self.dimensions()
+    }
+
+    /// 当前的背景图, png格式的base64字符串
+    #[pyo3(name = "background")]
+    fn py_background(&self, py: Python) -> PyResult<String> {
+        Ok(py.allow_threads(|| self.background().and_then(|img| encode_png_b64(&img)))?)
+    }
+
+    #[pyo3(name = "save")]
+    fn py_save(&self, py: Python, path: std::path::PathBuf) -> PyResult<()> {
+        Ok(py.allow_threads(|| self.save(path))?)
+    }
+
+    #[staticmethod]
+    #[pyo3(name = "load")]
+    fn py_load(py: Python, path: std::path::PathBuf) -> PyResult<BackgroundAccumulator> {
+        Ok(py.allow_threads(|| BackgroundAccumulator::load(path))?)
+    }
+}
+
+#[cfg(feature = "python")]
+#[pyproto]
+impl PyObjectProtocol for BackgroundAccumulator {
+    fn __repr__(&self) -> String {
+        format!("BackgroundAccumulator(count={}, size={:?}, sigma={}, warmup={})",
+                self.count, self.dimensions(), self.sigma, self.warmup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::background_accumulator::BackgroundAccumulator;
+    use crate::error::Error;
+    use crate::test_util::{load_images, temp_dir};
+    use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+
+    #[test]
+    fn test_accumulate() {
+        let input = load_images();
+        let mut acc = BackgroundAccumulator::new(2.5, 3).unwrap();
+        assert!(matches!(acc.background(), Err(Error::EmptyInput(_))));
+        for img in &input {
+            acc.add(img).unwrap();
+        }
+        assert_eq!(acc.count(), 4);
+        let output = acc.background().unwrap();
+        assert_eq!(output.dimensions(), input[0].dimensions());
+
+        // 尺寸不一样的图片会被缩放
+        acc.add(&input[0].resize_exact(150, 75, image::imageops::FilterType::Nearest)).unwrap();
+        assert_eq!(acc.dimensions(), input[0].dimensions());
+    }
+
+    #[test]
+    fn test_outlier_rejected() {
+        let mut acc = BackgroundAccumulator::new(2.5, 2).unwrap();
+        let gray = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(8, 8, Rgba([100, 100, 100, 255])));
+        for _ in 0..4 {
+            acc.add(&gray).unwrap();
+        }
+        // 一张带白块的图片, 白块不应该影响背景
+        let mut outlier = gray.clone();
+        outlier.put_pixel(3, 3, Rgba([255, 255, 255, 255]));
+        acc.add(&outlier).unwrap();
+        assert_eq!(acc.background().unwrap().get_pixel(3, 3), Rgba([100, 100, 100, 255]));
+    }
+
+    #[test]
+    fn test_warmup_outlier_rejected() {
+        // 预热样本里的第一张就带着白块, 凑够预热样本之后也要被筛掉
+        let gray = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(8, 8, Rgba([100, 100, 100, 255])));
+        let mut outlier = gray.clone();
+        outlier.put_pixel(3, 3, Rgba([255, 255, 255, 255]));
+        let mut acc = BackgroundAccumulator::new(2.5, 3).unwrap();
+        acc.add(&outlier).unwrap();
+        acc.add(&gray).unwrap();
+        // 预热还没结束的时候先用所有样本的均值
+        assert_ne!(acc.background().unwrap().get_pixel(3, 3), Rgba([100, 100, 100, 255]));
+        acc.add(&gray).unwrap();
+        assert_eq!(acc.background().unwrap().get_pixel(3, 3), Rgba([100, 100, 100, 255]));
+        assert_eq!(acc.background().unwrap().get_pixel(0, 0), Rgba([100, 100, 100, 255]));
+    }
+
+    #[test]
+    fn test_save_and_resume() {
+        let input = load_images();
+        let mut acc = BackgroundAccumulator::new(2.5, 3).unwrap();
+        acc.add(&input[0]).unwrap();
+        acc.add(&input[1]).unwrap();
+
+        let dir = temp_dir();
+        let path = dir.path().join("acc.bin");
+        acc.save(&path).unwrap();
+        let mut resumed = BackgroundAccumulator::load(&path).unwrap();
+
+        acc.add(&input[2]).unwrap();
+        resumed.add(&input[2]).unwrap();
+        assert_eq!(resumed.count(), 3);
+        assert_eq!(resumed.background().unwrap().to_rgba8(), acc.background().unwrap().to_rgba8());
+
+        assert!(matches!(BackgroundAccumulator::from_bytes(b"garbage"), Err(Error::Corrupted(_))));
+        let mut truncated = acc.to_bytes();
+        truncated.pop();
+        assert!(matches!(BackgroundAccumulator::from_bytes(&truncated), Err(Error::Corrupted(_))));
+    }
+
+    #[test]
+    fn test_resume_during_warmup() {
+        let input = load_images();
+        let mut acc = BackgroundAccumulator::new(2.5, 3).unwrap();
+        acc.add(&input[0]).unwrap();
+        let mut resumed = BackgroundAccumulator::from_bytes(&acc.to_bytes()).unwrap();
+        for img in &input[1..] {
+            acc.add(img).unwrap();
+            resumed.add(img).unwrap();
+        }
+        assert_eq!(resumed.background().unwrap().to_rgba8(), acc.background().unwrap().to_rgba8());
+    }
+
+    #[test]
+    fn test_corrupted_header() {
+        let mut acc = BackgroundAccumulator::new(2.5, 0).unwrap();
+        acc.add(&DynamicImage::new_rgba8(2, 2)).unwrap();
+        let data = acc.to_bytes();
+        let header = |offset: usize, value: &[u8]| {
+            let mut data = data.clone();
+            data[offset..offset + value.len()].copy_from_slice(value);
+            BackgroundAccumulator::from_bytes(&data)
+        };
+        // 宽高乘出来溢出
+        let huge = header(6, &[0xff; 8]);
+        assert!(matches!(huge, Err(Error::Corrupted(_))));
+        // sigma不是正数
+        assert!(matches!(header(14, &f64::NAN.to_le_bytes()), Err(Error::Corrupted(_))));
+        assert!(matches!(header(14, &(-1.0f64).to_le_bytes()), Err(Error::Corrupted(_))));
+        assert!(matches!(header(5, &[9]), Err(Error::Corrupted(_))));
+        assert!(matches!(BackgroundAccumulator::new(f64::INFINITY, 3), Err(Error::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn test_corrupted_pixels() {
+        let mut acc = BackgroundAccumulator::new(2.5, 0).unwrap();
+        acc.add(&DynamicImage::new_rgba8(2, 2)).unwrap();
+        let data = acc.to_bytes();
+        let pixel = |offset: usize, value: u64| {
+            let mut data = data.clone();
+            let offset = super::HEADER_LEN + offset * 8;
+            data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+            BackgroundAccumulator::from_bytes(&data)
+        };
+        assert!(pixel(0, 0).is_ok());
+        // 第一个像素`total`的样本数是0, 以前`background()`会除以0
+        let zero_count = pixel(8, 0);
+        assert!(matches!(zero_count, Err(Error::Corrupted(_))));
+        // 只有一个样本, 红色的和却超过255
+        assert!(matches!(pixel(0, 256), Err(Error::Corrupted(_))));
+        assert!(matches!(pixel(4, 255 * 255 + 1), Err(Error::Corrupted(_))));
+        // 被接受的样本比全部样本还多
+        assert!(matches!(pixel(9 + 8, 2), Err(Error::Corrupted(_))));
+    }
+}
@@ -0,0 +1,278 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use image::{DynamicImage, GenericImageView};
+use image::imageops::FilterType;
+use img_hash::ImageHash;
+#[cfg(feature = "python")]
+use pyo3::{prelude::*, PyObjectProtocol, PySequenceProtocol};
+
+use crate::error::{Error, Result};
+use crate::image_cluster::perceptual_hash;
+use crate::image_utils::rgb_diff;
+#[cfg(feature = "python")]
+use crate::{image_cluster::DEFAULT_MAX_DISTANCE, image_utils::encode_png_b64, input::ImageSource};
+
+const INDEX_FILE: &str = "index.tsv";
+const INDEX_HEADER: &str = "# image-magic background library v1";
+
+#[derive(Clone, Debug)]
+struct LibraryEntry {
+    id: String,
+    hash: ImageHash,
+    image: DynamicImage,
+}
+
+/// 一次查找的结果
+#[derive(Clone, Debug)]
+pub struct LibraryMatch {
+    pub id: String,
+    /// 感知哈希的汉明距离
+    pub hash_distance: u32,
+    /// 背景缩放到挑战图大小之后, 平均每个像素的`rgb_diff`
+    pub pixel_distance: f64,
+    pub background: DynamicImage,
+}
+
+/// 保存在磁盘上的背景图库, 一个目录里放png文件和一个索引文件
+///
+/// 索引文件每行是`id\t哈希(base64)`, 图片文件名是`id.png`。
+/// 图片在打开的时候全部加载到内存里, 克隆是浅拷贝, 可以放心地传给后台线程。
+#[cfg_attr(feature = "python", pyclass(module = "image_magic"))]
+#[derive(Clone, Debug)]
+pub struct BackgroundLibrary {
+    dir: PathBuf,
+    max_distance: u32,
+    entries: Arc<Vec<LibraryEntry>>,
+}
+
+/// id直接拼成文件名, 带路径分隔符或者`..`的话会读写到图库目录外面去
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && !id.contains(['/', '\\']) && !id.contains("..")
+}
+
+impl BackgroundLibrary {
+    /// 目录不存在的话会自动创建
+    pub fn open<P: AsRef<Path>>(dir: P, max_distance: u32) -> Result<BackgroundLibrary> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut entries = vec![];
+        let index = dir.join(INDEX_FILE);
+        if index.exists() {
+            for (line_no, line) in fs::read_to_string(&index)?.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let corrupted = || Error::Corrupted(format!("{}:{}: {}", index.display(), line_no + 1, line));
+                let (id, hash) = line.split_once('\t').ok_or_else(corrupted)?;
+                if !valid_id(id) {
+                    return Err(corrupted());
+                }
+                let hash = ImageHash::from_base64(hash).map_err(|_| corrupted())?;
+                let data = fs::read(dir.join(format!("{}.png", id)))?;
+                let image = image::load_from_memory(&data).map_err(Error::UnsupportedImage)?;
+                entries.push(LibraryEntry { id: id.to_string(), hash, image });
+            }
+        }
+        Ok(BackgroundLibrary { dir, max_distance, entries: Arc::new(entries) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.entries.iter().map(|e| e.id.clone()).collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&DynamicImage> {
+        self.entries.iter().find(|e| e.id == id).map(|e| &e.image)
+    }
+
+    /// 保存一张背景图, 返回它的id
+    pub fn add(&mut self, image: &DynamicImage) -> Result<String> {
+        if image.width() == 0 || image.height() == 0 {
+            return Err(Error::EmptyInput("image has no pixels".to_string()));
+        }
+        let mut seq = self.entries.len();
+        let id = loop {
+            let id = format!("bg_{:05}", seq);
+            if self.get(&id).is_none() && !self.dir.join(format!("{}.png", id)).exists() {
+                break id;
+            }
+            seq += 1;
+        };
+        image.save_with_format(self.dir.join(format!("{}.png", id)), image::ImageFormat::Png)
+            .map_err(Error::Encode)?;
+        let entry = LibraryEntry { id: id.clone(), hash: perceptual_hash(image), image: image.clone() };
+        Arc::make_mut(&mut self.entries).push(entry);
+        self.write_index()?;
+        Ok(id)
+    }
+
+    /// 删除一张背景图, 不存在的话返回false
+    pub fn remove(&mut self, id: &str) -> Result<bool> {
+        let before = self.entries.len();
+        Arc::make_mut(&mut self.entries).retain(|e| e.id != id);
+        if self.entries.len() == before {
+            return Ok(false);
+        }
+        self.write_index()?;
+        let file = self.dir.join(format!("{}.png", id));
+        if file.exists() {
+            fs::remove_file(file)?;
+        }
+        Ok(true)
+    }
+
+    /// 先按感知哈希筛选, 再比较像素差, 返回最接近的背景
+    pub fn find(&self, challenge: &DynamicImage) -> Option<LibraryMatch> {
+        let hash = perceptual_hash(challenge);
+        let (width, height) = challenge.dimensions();
+        self.entries.iter()
+            .map(|e| (e, e.hash.dist(&hash)))
+            .filter(|(_, hash_distance)| *hash_distance <= self.max_distance)
+            .map(|(e, hash_distance)| {
+                let background = if e.image.dimensions() != (width, height) {
+                    e.image.resize_exact(width, height, FilterType::Triangle)
+                } else {
+                    e.image.clone()
+                };
+                let total: u64 = challenge.pixels()
+                    .map(|(x, y, val)| rgb_diff(val, background.get_pixel(x, y)) as u64)
+                    .sum();
+                let pixel_distance = total as f64 / (width as f64 * height as f64).max(1.0);
+                LibraryMatch { id: e.id.clone(), hash_distance, pixel_distance, background }
+            })
+            .min_by(|l, r| l.pixel_distance.total_cmp(&r.pixel_distance))
+    }
+
+    /// 先写临时文件再改名, 避免写到一半的时候进程挂掉把索引弄坏
+    fn write_index(&self) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        let mut file = fs::File::create(&tmp)?;
+        writeln!(file, "{}", INDEX_HEADER)?;
+        for entry in self.entries.iter() {
+            writeln!(file, "{}\t{}", entry.id, entry.hash.to_base64())?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl BackgroundLibrary {
+    /// `max_distance`: 感知哈希的汉明距离超过这个值的背景不参与比较
+    #[new]
+    #[args(directory, max_distance = "DEFAULT_MAX_DISTANCE")]
+    fn py_new(py: Python, directory: PathBuf, max_distance: u32) -> PyResult<BackgroundLibrary> {
+        Ok(py.allow_threads(|| BackgroundLibrary::open(directory, max_distance))?)
+    }
+
+    #[getter]
+    fn directory(&self) -> PathBuf {
+        self.dir.clone()
+    }
+
+    #[getter(ids)]
+    fn py_ids(&self) -> Vec<String> {
+        self.ids()
+    }
+
+    /// 输入格式和`avg_b64`一样, 返回新背景的id
+    #[pyo3(name = "add")]
+    fn py_add(&mut self, py: Python, image: ImageSource) -> PyResult<String> {
+        Ok(py.allow_threads(|| image.decode().and_then(|img| self.add(&img)))?)
+    }
+
+    #[pyo3(name = "remove")]
+    fn py_remove(&mut self, id: &str) -> PyResult<bool> {
+        Ok(self.remove(id)?)
+    }
+
+    /// png格式的base64字符串, id不存在的话返回None
+    #[pyo3(name = "get")]
+    fn py_get(&self, id: &str) -> PyResult<Option<String>> {
+        Ok(self.get(id).map(encode_png_b64).transpose()?)
+    }
+
+    /// 返回`(id, hash_distance, pixel_distance)`, 没有匹配的背景时返回None
+    #[pyo3(name = "find")]
+    fn py_find(&self, py: Python, image: ImageSource) -> PyResult<Option<(String, u32, f64)>> {
+        let found = py.allow_threads(|| image.decode().map(|img| self.find(&img)))?;
+        Ok(found.map(|m| (m.id, m.hash_distance, m.pixel_distance)))
+    }
+}
+
+#[cfg(feature = "python")]
+#[pyproto]
+impl PySequenceProtocol for BackgroundLibrary {
+    fn __len__(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(feature = "python")]
+#[pyproto]
+impl PyObjectProtocol for BackgroundLibrary {
+    fn __repr__(&self) -> String {
+        format!("BackgroundLibrary(directory={:?}, count={}, max_distance={})", self.dir, self.len(), self.max_distance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::background_library::BackgroundLibrary;
+    use crate::error::Error;
+    use crate::image_avg_merger::{avg, MergeOptions};
+    use crate::test_util::{load_images, temp_dir};
+
+    #[test]
+    fn test_library() {
+        let temp = temp_dir();
+        let dir = temp.path().join("library");
+        let input = load_images();
+        let background = avg(&input, &MergeOptions::default()).unwrap();
+
+        let mut library = BackgroundLibrary::open(&dir, 10).unwrap();
+        assert!(library.find(&input[0]).is_none());
+        let flipped = library.add(&background.fliph()).unwrap();
+        let id = library.add(&background).unwrap();
+        assert_ne!(flipped, id);
+
+        // 重新打开之后索引还在
+        let library = BackgroundLibrary::open(&dir, 10).unwrap();
+        assert_eq!(library.ids(), vec![flipped.clone(), id.clone()]);
+        let found = library.find(&input[1]).unwrap();
+        assert_eq!(found.id, id);
+        assert!(found.pixel_distance < 10.0);
+
+        let mut library = library;
+        assert!(library.remove(&id).unwrap());
+        assert!(!library.remove(&id).unwrap());
+        let library = BackgroundLibrary::open(&dir, 10).unwrap();
+        assert_eq!(library.ids(), vec![flipped]);
+        assert!(library.find(&input[1]).is_none());
+    }
+
+    #[test]
+    fn test_corrupted_index() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        std::fs::write(dir.join("index.tsv"), "bg_00000 no-hash-here\n").unwrap();
+        assert!(matches!(BackgroundLibrary::open(dir, 10), Err(Error::Corrupted(_))));
+        for id in ["../../escape", "sub/bg", "sub\\bg", ".."] {
+            std::fs::write(dir.join("index.tsv"), format!("{}\tAAAAAAAAAAA=\n", id)).unwrap();
+            assert!(matches!(BackgroundLibrary::open(dir, 10), Err(Error::Corrupted(_))));
+        }
+    }
+}
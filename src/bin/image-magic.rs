@@ -0,0 +1,431 @@
+//! `image-magic`命令行工具, 不写Python也能在shell里合成背景、找目标、画结果
+//!
+//! 结果以JSON打印到stdout, 出错的时候在stderr打印`{"error": ...}`并返回1
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, GenericImageView};
+use image_magic::{annotate, find_peaks, find_peaks_with_debug, has_image_extension, merge_backgrounds, BackgroundLibrary,
+                  BoundingBox, Error, HilltopParamAndResult, ImageSource, MergeOptions, MergeStatistic, Point, Result,
+                  StopRule, DEFAULT_MAX_DISTANCE};
+use rustc_serialize::json::{Json, ToJson};
+
+const USAGE: &str = "\
+usage: image-magic <command> [options]
+
+commands:
+  avg <dir|glob|file|->... -o <output> [--statistic trimmed_mean] [--ratio 0.85]
+      合成背景图, 目录里的所有图片或者匹配通配符(*/?)的文件都会参与合成
+  top-n --cg <file|-> (--bg <file|-> | --library <dir>) --ch-size <n> [--top-n 3]
+        [--size-strategy resize] [--metric l1] [--ignore l,t,r,b;...] [--ignore-mask <file>]
+        [--roi l,t,r,b] [--min-peak-ratio <f>] [--min-avg-ratio <f>]
+        [--min-distance <n>] [--suppression circle] [--subpixel] [--debug-dir <dir>]
+      打印{\"points\": [...]}
+  annotate --cg <file|-> [--points <json|->] [--bg <file>] -o <output>
+      把top-n的结果画在挑战图上, --points默认从stdin读top-n的输出, 图片从stdin读的时候必须传--points
+
+`-`表示从stdin读, 同一条命令里只能用一次";
+
+/// 解析好的命令行参数
+#[derive(Debug, Default)]
+struct Args {
+    positional: Vec<String>,
+    options: BTreeMap<String, String>,
+    flags: BTreeSet<String>,
+}
+
+impl Args {
+    /// `options`是带值的选项, `flags`是开关, 不认识的选项报错; `-o`是`--output`的简写
+    fn parse(args: &[String], options: &[&str], flags: &[&str]) -> Result<Args> {
+        let mut parsed = Args::default();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let name = match arg.as_str() {
+                "-o" => "output",
+                _ => match arg.strip_prefix("--") {
+                    Some(name) => name,
+                    None => {
+                        parsed.positional.push(arg.clone());
+                        continue;
+                    }
+                },
+            };
+            let (name, inline_value) = match name.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (name, None),
+            };
+            if flags.contains(&name) && inline_value.is_none() {
+                parsed.flags.insert(name.to_string());
+            } else if options.contains(&name) {
+                let value = match inline_value {
+                    Some(value) => value,
+                    None => iter.next().cloned()
+                        .ok_or_else(|| Error::InvalidParameter(format!("--{} requires a value", name)))?,
+                };
+                parsed.options.insert(name.to_string(), value);
+            } else {
+                return Err(Error::InvalidParameter(format!("unknown option: {}", arg)));
+            }
+        }
+        let stdin_count = parsed.positional.iter().chain(parsed.options.values()).filter(|v| *v == "-").count();
+        if stdin_count > 1 {
+            return Err(Error::InvalidParameter("stdin (-) can only be used once".to_string()));
+        }
+        Ok(parsed)
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn required(&self, name: &str) -> Result<&str> {
+        self.get(name).ok_or_else(|| Error::InvalidParameter(format!("--{} is required", name)))
+    }
+
+    fn value<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>> {
+        self.get(name).map(|value| {
+            value.parse().map_err(|_| Error::InvalidParameter(format!("invalid value for --{}: {}", name, value)))
+        }).transpose()
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains(name)
+    }
+}
+
+/// `-`读stdin, 其他当作文件路径
+fn read_image(path: &str) -> Result<ImageSource> {
+    if path == "-" {
+        let mut data = vec![];
+        std::io::stdin().read_to_end(&mut data)?;
+        return Ok(ImageSource::Bytes(data));
+    }
+    Ok(ImageSource::Path(PathBuf::from(path)))
+}
+
+fn read_text(path: &str) -> Result<String> {
+    if path == "-" {
+        let mut text = String::new();
+        std::io::stdin().read_to_string(&mut text)?;
+        return Ok(text);
+    }
+    Ok(fs::read_to_string(path)?)
+}
+
+/// 只支持`*`和`?`
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => wildcard_match(&pattern[1..], name) || (!name.is_empty() && wildcard_match(pattern, &name[1..])),
+        (Some('?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+fn sorted_files<F: Fn(&str) -> bool>(dir: &Path, filter: F) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        if path.is_file() && !name.starts_with('.') && filter(name) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// `avg`的输入: 目录展开成里面的文件, 文件名带通配符的展开成匹配的文件
+fn expand_inputs(inputs: &[String]) -> Result<Vec<ImageSource>> {
+    let mut sources = vec![];
+    for input in inputs {
+        let path = Path::new(input);
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        if input == "-" {
+            sources.push(read_image(input)?);
+        } else if path.is_dir() {
+            // 目录里只取图片, 同目录下的索引、说明之类的文件跳过
+            let files = sorted_files(path, |file| has_image_extension(Path::new(file)))?;
+            sources.extend(files.into_iter().map(ImageSource::Path));
+        } else if name.contains(['*', '?']) {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let pattern: Vec<char> = name.chars().collect();
+            let matched = sorted_files(dir, |file| wildcard_match(&pattern, &file.chars().collect::<Vec<_>>()))?;
+            if matched.is_empty() {
+                return Err(Error::EmptyInput(format!("no files match {}", input)));
+            }
+            sources.extend(matched.into_iter().map(ImageSource::Path));
+        } else {
+            sources.push(ImageSource::Path(path.to_path_buf()));
+        }
+    }
+    if sources.is_empty() {
+        return Err(Error::EmptyInput("no input images".to_string()));
+    }
+    Ok(sources)
+}
+
+/// `left,top,right,bottom`
+fn parse_rect(text: &str) -> Result<BoundingBox> {
+    let values: Vec<usize> = text.split(',').map(|v| v.trim().parse()).collect::<std::result::Result<_, _>>()
+        .map_err(|_| Error::InvalidParameter(format!("invalid rect: {}", text)))?;
+    match values[..] {
+        [left, top, right, bottom] => Ok((left, top, right, bottom)),
+        _ => Err(Error::InvalidParameter(format!("rect needs 4 values: {}", text))),
+    }
+}
+
+/// `Point`的JSON格式(`top-n`的输出)转回来, 只有`x`和`y`是必须的
+fn point_from_json(json: &Json) -> Result<Point> {
+    let invalid = || Error::InvalidParameter(format!("invalid point: {}", json));
+    let field = |key: &str| json.find(key).and_then(Json::as_u64).map(|v| v as usize);
+    let (x, y) = (field("x").ok_or_else(invalid)?, field("y").ok_or_else(invalid)?);
+    let bbox = match json.find("bbox").and_then(Json::as_array) {
+        Some(bbox) => {
+            let bbox = bbox.iter().map(|v| v.as_u64().map(|v| v as usize)).collect::<Option<Vec<_>>>();
+            match bbox.as_deref() {
+                Some(&[left, top, right, bottom]) if left <= right && top <= bottom => (left, top, right, bottom),
+                _ => return Err(invalid()),
+            }
+        }
+        None => (x, y, x, y),
+    };
+    let float = |key: &str, default: f64| json.find(key).and_then(Json::as_f64).unwrap_or(default);
+    Ok(Point {
+        x,
+        y,
+        weight: field("weight").unwrap_or(0),
+        confidence: float("confidence", 0.0),
+        bbox,
+        rank: field("rank").unwrap_or(0),
+        fx: float("fx", x as f64),
+        fy: float("fy", y as f64),
+    })
+}
+
+fn save_image(image: &DynamicImage, output: &str) -> Result<()> {
+    image.save(output).map_err(Error::Encode)
+}
+
+fn object(entries: Vec<(&str, Json)>) -> Json {
+    Json::Object(entries.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+fn run_avg(args: &[String]) -> Result<Json> {
+    let args = Args::parse(args, &["output", "statistic", "ratio"], &[])?;
+    let output = args.required("output")?;
+    let statistic = match (args.value("statistic")?.unwrap_or(MergeOptions::default().statistic()), args.value("ratio")?) {
+        (MergeStatistic::TrimmedMean { .. }, Some(ratio)) => MergeStatistic::TrimmedMean { ratio },
+        (MergeStatistic::WinsorizedMean { .. }, Some(ratio)) => MergeStatistic::WinsorizedMean { ratio },
+        (_, Some(_)) => return Err(Error::InvalidParameter("--ratio only applies to trimmed_mean and winsorized_mean".to_string())),
+        (statistic, None) => statistic,
+    };
+    let options = MergeOptions::new(statistic)?;
+    let images = expand_inputs(&args.positional)?.into_iter().map(ImageSource::decode).collect::<Result<Vec<_>>>()?;
+    let result = merge_backgrounds(&images, &options)?;
+    save_image(&result, output)?;
+    Ok(object(vec![
+        ("output", output.to_json()),
+        ("width", result.width().to_json()),
+        ("height", result.height().to_json()),
+        ("inputs", images.len().to_json()),
+    ]))
+}
+
+fn run_find(args: &[String]) -> Result<Json> {
+    let args = Args::parse(args, &["bg", "cg", "library", "ch-size", "top-n", "size-strategy", "metric", "ignore",
+        "ignore-mask", "roi", "min-peak-ratio", "min-avg-ratio", "min-distance", "suppression", "debug-dir"], &["subpixel"])?;
+    let cg_image = read_image(args.required("cg")?)?.decode()?;
+    let bg_image = match (args.get("bg"), args.get("library")) {
+        (Some(bg), _) => read_image(bg)?.decode()?,
+        (None, Some(dir)) => BackgroundLibrary::open(dir, DEFAULT_MAX_DISTANCE)?.find(&cg_image)
+            .ok_or_else(|| Error::NotFound("no matching background in library".to_string()))?
+            .background,
+        (None, None) => return Err(Error::InvalidParameter("either --bg or --library is required".to_string())),
+    };
+    let ch_size = args.value("ch-size")?.ok_or_else(|| Error::InvalidParameter("--ch-size is required".to_string()))?;
+    let mut params = HilltopParamAndResult::new(bg_image, cg_image, ch_size, args.value("top-n")?.unwrap_or(3))
+        .with_size_strategy(args.value("size-strategy")?.unwrap_or_default())
+        .with_diff_metric(args.value("metric")?.unwrap_or_default())
+        .with_stop_rule(StopRule { min_peak_ratio: args.value("min-peak-ratio")?, min_avg_ratio: args.value("min-avg-ratio")? })
+        .with_subpixel(args.flag("subpixel"));
+    if let Some(rects) = args.get("ignore") {
+        for rect in rects.split(';').filter(|rect| !rect.trim().is_empty()) {
+            params = params.with_ignore_rect(parse_rect(rect)?);
+        }
+    }
+    if let Some(mask) = args.get("ignore-mask") {
+        params = params.with_ignore_mask(read_image(mask)?.decode()?);
+    }
+    if let Some(roi) = args.get("roi") {
+        params = params.with_roi(parse_rect(roi)?);
+    }
+    if let Some(min_distance) = args.value("min-distance")? {
+        params = params.with_min_distance(min_distance, args.value("suppression")?.unwrap_or_default());
+    }
+    let points = match args.get("debug-dir") {
+        Some(debug_dir) => {
+            let (points, debug) = find_peaks_with_debug(params)?;
+            debug.write_to_dir(debug_dir)?;
+            points
+        }
+        None => find_peaks(params)?,
+    };
+    Ok(object(vec![("points", points.iter().map(Point::to_json).collect::<Vec<_>>().to_json())]))
+}
+
+fn run_annotate(args: &[String]) -> Result<Json> {
+    let args = Args::parse(args, &["cg", "bg", "points", "output"], &[])?;
+    let output = args.required("output")?;
+    // 不传--points的时候从stdin读, 图片也从stdin读的话就冲突了
+    if args.get("points").is_none() && [args.get("cg"), args.get("bg")].contains(&Some("-")) {
+        return Err(Error::InvalidParameter("--points is required when an image is read from stdin".to_string()));
+    }
+    let cg_image = read_image(args.required("cg")?)?.decode()?;
+    let background = args.get("bg").map(|bg| read_image(bg)?.decode()).transpose()?;
+    let text = read_text(args.get("points").unwrap_or("-"))?;
+    let json = Json::from_str(&text).map_err(|e| Error::InvalidParameter(format!("invalid points json: {}", e)))?;
+    // 既可以是`top-n`的输出, 也可以直接是点的数组
+    let points = match json.find("points").unwrap_or(&json).as_array() {
+        Some(points) => points.iter().map(point_from_json).collect::<Result<Vec<_>>>()?,
+        None => return Err(Error::InvalidParameter("points json must be an array or {\"points\": [...]}".to_string())),
+    };
+    let result = annotate(&cg_image, &points, background.as_ref())?;
+    save_image(&DynamicImage::ImageRgba8(result), output)?;
+    Ok(object(vec![("output", output.to_json()), ("points", points.len().to_json())]))
+}
+
+/// 执行一条命令, 返回要打印的JSON
+fn run_command(args: &[String]) -> Result<Json> {
+    let (command, rest) = args.split_first()
+        .ok_or_else(|| Error::InvalidParameter(format!("missing command\n{}", USAGE)))?;
+    match command.as_str() {
+        "avg" => run_avg(rest),
+        "top-n" => run_find(rest),
+        "annotate" => run_annotate(rest),
+        _ => Err(Error::InvalidParameter(format!("unknown command: {}\n{}", command, USAGE))),
+    }
+}
+
+/// 下游是`head`之类提前关掉管道的命令的时候不panic
+fn print_stdout(text: &str) {
+    let _ = writeln!(std::io::stdout(), "{}", text);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if matches!(args.first().map(String::as_str), Some("help" | "-h" | "--help")) {
+        print_stdout(USAGE);
+        return;
+    }
+    match run_command(&args) {
+        Ok(json) => print_stdout(&json.pretty().to_string()),
+        Err(e) => {
+            eprintln!("{}", object(vec![("error", e.to_string().to_json())]));
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rect, point_from_json, run_command, wildcard_match, Args};
+    use image::GenericImageView;
+    use image_magic::Error;
+    use rustc_serialize::json::{Json, ToJson};
+    use std::fs;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = Args::parse(&strings(&["a", "--ch-size", "40", "--top-n=2", "-o", "out.png", "--subpixel"]),
+                               &["ch-size", "top-n", "output"], &["subpixel"]).unwrap();
+        assert_eq!(args.positional, strings(&["a"]));
+        assert_eq!(args.value::<usize>("ch-size").unwrap(), Some(40));
+        assert_eq!(args.value::<usize>("top-n").unwrap(), Some(2));
+        assert_eq!(args.get("output"), Some("out.png"));
+        assert!(args.flag("subpixel"));
+        assert!(args.value::<usize>("output").is_err());
+
+        assert!(Args::parse(&strings(&["--unknown", "1"]), &["ch-size"], &[]).is_err());
+        assert!(Args::parse(&strings(&["--ch-size"]), &["ch-size"], &[]).is_err());
+        assert!(Args::parse(&strings(&["-", "--cg", "-"]), &["cg"], &[]).is_err());
+        assert_eq!(parse_rect("1, 2,3,4").unwrap(), (1, 2, 3, 4));
+        assert!(parse_rect("1,2,3").is_err());
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        let matches = |pattern: &str, name: &str| {
+            wildcard_match(&pattern.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>())
+        };
+        assert!(matches("*.jpg", "0.jpg"));
+        assert!(matches("?.jpg", "1.jpg"));
+        assert!(matches("a*b*c", "aXXbYc"));
+        assert!(!matches("*.jpg", "0.png"));
+        assert!(!matches("?.jpg", "10.jpg"));
+    }
+
+    #[test]
+    fn test_point_json() {
+        let found = run_command(&strings(&["top-n", "--bg", "./src/images/1.jpg", "--cg", "./src/images/0.jpg",
+            "--ch-size", "40", "--top-n", "1", "--subpixel"])).unwrap();
+        let point = point_from_json(&found["points"][0]).unwrap();
+        assert_eq!(point_from_json(&point.to_json()).unwrap(), point);
+
+        // bbox里有不是整数的值、不够四个值或者左右上下颠倒的都不接受
+        for bbox in ["[1, 2, 3.5, 4]", "[1, 2, -3, 4]", "[1, 2, 3]", "[5, 2, 3, 4]", "[1, 6, 3, 4]"] {
+            let json = Json::from_str(&format!("{{\"x\": 2, \"y\": 3, \"bbox\": {}}}", bbox)).unwrap();
+            assert!(matches!(point_from_json(&json), Err(Error::InvalidParameter(_))), "{}", bbox);
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let bg = dir.join("bg.png").to_str().unwrap().to_string();
+        let result = run_command(&strings(&["avg", "./src/images/?.jpg", "-o", &bg])).unwrap();
+        assert_eq!(result["inputs"], Json::U64(4));
+        assert_eq!(image::open(&bg).unwrap().dimensions(), (300, 150));
+
+        let found = run_command(&strings(&["top-n", "--bg", &bg, "--cg", "./src/images/0.jpg", "--ch-size", "40"])).unwrap();
+        let points = found["points"].as_array().unwrap();
+        assert_eq!(points.len(), 3);
+        assert_eq!((&points[0]["x"], &points[0]["y"]), (&Json::U64(111), &Json::U64(40)));
+
+        let points_file = dir.join("points.json");
+        fs::write(&points_file, found.to_string()).unwrap();
+        let output = dir.join("annotated.png").to_str().unwrap().to_string();
+        let annotated = run_command(&strings(&["annotate", "--cg", "./src/images/0.jpg", "--points",
+            points_file.to_str().unwrap(), "-o", &output])).unwrap();
+        assert_eq!(annotated["points"], Json::U64(3));
+        assert_eq!(image::open(&output).unwrap().dimensions(), (300, 150));
+        // 没有--points的时候stdin留给点的JSON
+        let err = run_command(&strings(&["annotate", "--cg", "-", "-o", &output])).unwrap_err();
+        assert!(matches!(err, Error::InvalidParameter(_)));
+
+        // 目录里不是图片的文件不参与合成
+        let inputs = dir.join("inputs");
+        fs::create_dir_all(&inputs).unwrap();
+        for i in 0..2 {
+            fs::copy(format!("./src/images/{}.jpg", i), inputs.join(format!("{}.JPG", i))).unwrap();
+        }
+        fs::write(inputs.join("notes.txt"), "not an image").unwrap();
+        let result = run_command(&strings(&["avg", inputs.to_str().unwrap(), "-o", &bg])).unwrap();
+        assert_eq!(result["inputs"], Json::U64(2));
+
+        assert!(run_command(&strings(&["top-n", "--cg", "./src/images/0.jpg", "--ch-size", "40"])).is_err());
+        assert!(run_command(&strings(&["unknown"])).is_err());
+    }
+}
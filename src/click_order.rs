@@ -0,0 +1,422 @@
+use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgba, RgbaImage};
+use image::imageops::{self, FilterType};
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use imageproc::filter::gaussian_blur_f32;
+use imageproc::gradients::sobel_gradients;
+
+use crate::error::{Error, Result};
+use crate::image_hill_top_v2::Point;
+use crate::image_utils::rgb_diff;
+
+/// 比较相似度时图标和目标区域统一缩放到这个边长
+const PATCH_SIZE: u32 = 32;
+/// 图标和背景的亮度差超过这个值算图标的像素
+const INK_THRESHOLD: i32 = 40;
+/// 图标允许的旋转角度(度)
+const ROTATIONS: [f32; 9] = [-40.0, -30.0, -20.0, -10.0, 0.0, 10.0, 20.0, 30.0, 40.0];
+/// 图标在目标区域里占的比例
+const SCALES: [f32; 4] = [0.6, 0.75, 0.9, 1.0];
+/// 边缘图模糊的程度
+const EDGE_BLUR: f32 = 1.5;
+/// 目标区域不超过这个数的时候穷举所有分配方式, 否则贪心
+const MAX_BRUTE_FORCE: usize = 8;
+
+/// 提示图里的一个图标, 在提示图里的外接矩形`(left, top, right, bottom)`
+pub type IconRect = (u32, u32, u32, u32);
+
+/// 提示图背景: 四条边上像素亮度的中位数, 背景是透明的话返回None
+fn hint_background(hint: &DynamicImage) -> Option<u8> {
+    let (width, height) = hint.dimensions();
+    let mut border = vec![];
+    for x in 0..width {
+        border.push(hint.get_pixel(x, 0));
+        border.push(hint.get_pixel(x, height - 1));
+    }
+    for y in 0..height {
+        border.push(hint.get_pixel(0, y));
+        border.push(hint.get_pixel(width - 1, y));
+    }
+    let transparent = border.iter().filter(|p| p[3] < 128).count();
+    if transparent * 2 > border.len() {
+        return None;
+    }
+    let mut luma: Vec<u8> = border.iter().map(|p| to_luma(*p)).collect();
+    luma.sort_unstable();
+    Some(luma[luma.len() / 2])
+}
+
+fn to_luma(pixel: Rgba<u8>) -> u8 {
+    (0.299 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.114 * pixel[2] as f64).round() as u8
+}
+
+/// 按列投影把提示图切成一个个图标, 从左到右排列。
+///
+/// 和背景差异大的像素(透明背景的话就是不透明的像素)算图标, 有这种像素的列连起来就是一个图标,
+/// 中间隔一两列空白的会合并, 太窄的当成噪点丢掉。
+pub fn split_hint(hint: &DynamicImage) -> Result<Vec<IconRect>> {
+    let (width, height) = hint.dimensions();
+    if width == 0 || height == 0 {
+        return Err(Error::EmptyInput("hint image has no pixels".to_string()));
+    }
+    let background = hint_background(hint);
+    let is_ink = |x: u32, y: u32| {
+        let pixel = hint.get_pixel(x, y);
+        match background {
+            Some(bg) => pixel[3] >= 128 && (to_luma(pixel) as i32 - bg as i32).abs() > INK_THRESHOLD,
+            None => pixel[3] >= 128,
+        }
+    };
+    let min_ink = (height / 20).max(1);
+    let ink_columns: Vec<bool> = (0..width)
+        .map(|x| (0..height).filter(|&y| is_ink(x, y)).count() as u32 >= min_ink)
+        .collect();
+
+    let mut segments: Vec<(u32, u32)> = vec![];
+    for (x, &ink) in ink_columns.iter().enumerate() {
+        if !ink {
+            continue;
+        }
+        let x = x as u32;
+        match segments.last_mut() {
+            Some(last) if x - last.1 <= 3 => last.1 = x,
+            _ => segments.push((x, x)),
+        }
+    }
+
+    let mut icons = vec![];
+    for (left, right) in segments {
+        if right - left + 1 < 3 {
+            continue;
+        }
+        let rows: Vec<u32> = (0..height).filter(|&y| (left..=right).any(|x| is_ink(x, y))).collect();
+        if let (Some(&top), Some(&bottom)) = (rows.first(), rows.last()) {
+            icons.push((left, top, right, bottom));
+        }
+    }
+    Ok(icons)
+}
+
+/// 边缘强度图, 多个通道的话每个像素取各通道里最大的梯度, 颜色不同但亮度接近的边缘也不会丢。
+/// 去均值后归一化成单位向量, 两个向量的点积就是归一化互相关;
+/// 边缘先模糊一下, 位置差几个像素也能对得上
+fn edge_vector(channels: &[GrayImage]) -> Vec<f64> {
+    let mut edges = sobel_gradients(&channels[0]);
+    for channel in &channels[1..] {
+        for (edge, other) in edges.pixels_mut().zip(sobel_gradients(channel).pixels()) {
+            edge[0] = edge[0].max(other[0]);
+        }
+    }
+    let edges = gaussian_blur_f32(&edges, EDGE_BLUR);
+    let mut data: Vec<f64> = edges.pixels().map(|p| p[0] as f64).collect();
+    let mean = data.iter().sum::<f64>() / data.len() as f64;
+    data.iter_mut().for_each(|val| *val -= mean);
+    let norm = data.iter().map(|val| val * val).sum::<f64>().sqrt();
+    if norm > 0.0 {
+        data.iter_mut().for_each(|val| *val /= norm);
+    }
+    data
+}
+
+/// 一个图标在不同旋转角度和缩放比例下的所有模板
+fn icon_templates(hint: &DynamicImage, icon: IconRect, background: u8) -> Vec<Vec<f64>> {
+    let (left, top, right, bottom) = icon;
+    let gray = hint.crop_imm(left, top, right - left + 1, bottom - top + 1).to_luma8();
+    // 补成以图标重心为中心的正方形, 和`find_top_n`找到的点(差值的重心附近)对齐, 也保持了长宽比
+    let (mut sum_x, mut sum_y, mut count) = (0.0, 0.0, 0.0);
+    for (x, y, pixel) in gray.enumerate_pixels() {
+        if (pixel[0] as i32 - background as i32).abs() > INK_THRESHOLD {
+            sum_x += x as f64 + 0.5;
+            sum_y += y as f64 + 0.5;
+            count += 1.0;
+        }
+    }
+    let (cx, cy) = if count > 0.0 {
+        (sum_x / count, sum_y / count)
+    } else {
+        (gray.width() as f64 / 2.0, gray.height() as f64 / 2.0)
+    };
+    let half = cx.max(gray.width() as f64 - cx).max(cy).max(gray.height() as f64 - cy).ceil() as u32;
+    let side = half * 2;
+    let mut square = GrayImage::from_pixel(side, side, Luma([background]));
+    imageops::overlay(&mut square, &gray, (half as f64 - cx).round() as u32, (half as f64 - cy).round() as u32);
+
+    let mut templates = vec![];
+    for scale in SCALES {
+        let size = ((PATCH_SIZE as f32 * scale).round() as u32).max(4);
+        let resized = imageops::resize(&square, size, size, FilterType::Triangle);
+        let mut canvas = GrayImage::from_pixel(PATCH_SIZE, PATCH_SIZE, Luma([background]));
+        let offset = (PATCH_SIZE - size) / 2;
+        imageops::overlay(&mut canvas, &resized, offset, offset);
+        for angle in ROTATIONS {
+            let rotated = rotate_about_center(&canvas, angle.to_radians(), Interpolation::Bilinear, Luma([background]));
+            templates.push(edge_vector(&[rotated]));
+        }
+    }
+    templates
+}
+
+/// 用来截取目标区域的通道: 有底图的话用和底图的差值, 目标的轮廓不受背景纹理干扰;
+/// 没有的话用挑战图的RGB三个通道
+fn region_source(challenge: &DynamicImage, background: Option<&DynamicImage>) -> Vec<GrayImage> {
+    let (width, height) = challenge.dimensions();
+    let background = match background {
+        Some(background) => background,
+        None => {
+            let rgb = challenge.to_rgb8();
+            return (0..3).map(|c| GrayImage::from_fn(width, height, |x, y| Luma([rgb.get_pixel(x, y)[c]]))).collect();
+        }
+    };
+    let background = if background.dimensions() == (width, height) {
+        background.clone()
+    } else {
+        background.resize_exact(width, height, FilterType::Triangle)
+    };
+    vec![GrayImage::from_fn(width, height, |x, y| {
+        Luma([(rgb_diff(challenge.get_pixel(x, y), background.get_pixel(x, y)) / 3) as u8])
+    })]
+}
+
+/// 以`point`为中心、边长`ch_size`的区域, `order_by_hint`已经检查过图片不是空的、点在图片里面
+fn region_vector(source: &[GrayImage], point: &Point, ch_size: u32) -> Vec<f64> {
+    let (width, height) = source[0].dimensions();
+    let half = ch_size / 2;
+    let (x, y) = (point.x as u32, point.y as u32);
+    let left = x.saturating_sub(half);
+    let top = y.saturating_sub(half);
+    let right = x.saturating_add(half).min(width - 1);
+    let bottom = y.saturating_add(half).min(height - 1);
+    let channels: Vec<GrayImage> = source.iter().map(|channel| {
+        let region = imageops::crop_imm(channel, left, top, right - left + 1, bottom - top + 1).to_image();
+        imageops::resize(&region, PATCH_SIZE, PATCH_SIZE, FilterType::Triangle)
+    }).collect();
+    edge_vector(&channels)
+}
+
+/// 穷举每个提示图标分配给哪个目标区域, 总相似度最大; 区域太多的时候退化成贪心
+fn solve_assignment(similarity: &[Vec<f64>]) -> Vec<usize> {
+    let hints = similarity.len();
+    let regions = similarity.first().map_or(0, |row| row.len());
+    if regions <= MAX_BRUTE_FORCE {
+        let mut best = (f64::MIN, vec![]);
+        let mut current = vec![];
+        let mut used = vec![false; regions];
+        search(similarity, 0.0, &mut current, &mut used, &mut best);
+        return best.1;
+    }
+
+    let mut pairs: Vec<(usize, usize)> = (0..hints).flat_map(|h| (0..regions).map(move |r| (h, r))).collect();
+    pairs.sort_by(|a, b| similarity[b.0][b.1].total_cmp(&similarity[a.0][a.1]));
+    let mut assignment = vec![usize::MAX; hints];
+    let mut used = vec![false; regions];
+    for (h, r) in pairs {
+        if assignment[h] == usize::MAX && !used[r] {
+            assignment[h] = r;
+            used[r] = true;
+        }
+    }
+    assignment
+}
+
+fn search(similarity: &[Vec<f64>], total: f64, current: &mut Vec<usize>, used: &mut [bool], best: &mut (f64, Vec<usize>)) {
+    if current.len() == similarity.len() {
+        if total > best.0 {
+            *best = (total, current.clone());
+        }
+        return;
+    }
+    let hint = current.len();
+    for region in 0..used.len() {
+        if used[region] {
+            continue;
+        }
+        used[region] = true;
+        current.push(region);
+        search(similarity, total + similarity[hint][region], current, used, best);
+        current.pop();
+        used[region] = false;
+    }
+}
+
+/// 点选验证码: 按提示图里图标的顺序排列`find_top_n`找到的目标。
+///
+/// 提示图按列投影切成图标, 每个目标取中心附近`ch_size`大小的区域,
+/// 图标在几个旋转角度和缩放比例下和区域比较边缘图的相关系数, 取最大的作为相似度,
+/// 最后求总相似度最大的一一对应关系。目标比图标多的时候, 多出来的目标会被丢掉。
+///
+/// `background`: `find_top_n`用的底图, 传了的话目标区域用差值图, 背景纹理复杂的时候准确很多
+///
+/// 返回的点`rank`按提示图的顺序重新从1编号
+pub fn order_by_hint(hint: &DynamicImage, challenge: &DynamicImage, background: Option<&DynamicImage>,
+                     points: &[Point], ch_size: u32) -> Result<Vec<Point>> {
+    let icons = split_hint(hint)?;
+    if icons.is_empty() {
+        return Err(Error::EmptyInput("no icon found in hint image".to_string()));
+    }
+    if points.len() < icons.len() {
+        return Err(Error::InvalidParameter(format!("{} icons in hint image but only {} points", icons.len(), points.len())));
+    }
+    if ch_size == 0 {
+        return Err(Error::InvalidParameter("ch_size must be greater than 0".to_string()));
+    }
+    let (width, height) = challenge.dimensions();
+    if width == 0 || height == 0 || background.is_some_and(|bg| bg.width() == 0 || bg.height() == 0) {
+        return Err(Error::EmptyInput("challenge or background image has no pixels".to_string()));
+    }
+    if let Some(point) = points.iter().find(|p| p.x >= width as usize || p.y >= height as usize) {
+        return Err(Error::InvalidParameter(format!("point ({}, {}) is outside the {}x{} challenge image",
+                                                   point.x, point.y, width, height)));
+    }
+    // 透明背景的提示图, 图标以外当成白色
+    let (hint, hint_bg) = match hint_background(hint) {
+        Some(hint_bg) => (hint.clone(), hint_bg),
+        None => {
+            let mut flat = RgbaImage::from_pixel(hint.width(), hint.height(), Rgba([255, 255, 255, 255]));
+            imageops::overlay(&mut flat, &hint.to_rgba8(), 0, 0);
+            (DynamicImage::ImageRgba8(flat), 255)
+        }
+    };
+
+    let source = region_source(challenge, background);
+    let regions: Vec<Vec<f64>> = points.iter().map(|point| region_vector(&source, point, ch_size)).collect();
+    let similarity: Vec<Vec<f64>> = icons.iter().map(|icon| {
+        let templates = icon_templates(&hint, *icon, hint_bg);
+        regions.iter().map(|region| {
+            templates.iter()
+                .map(|template| template.iter().zip(region).map(|(a, b)| a * b).sum::<f64>())
+                .fold(f64::MIN, f64::max)
+        }).collect()
+    }).collect();
+
+    Ok(solve_assignment(&similarity).into_iter().enumerate().map(|(i, index)| {
+        let mut point = points[index];
+        point.rank = i + 1;
+        point
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::click_order::{order_by_hint, solve_assignment, split_hint};
+    use crate::error::Error;
+    use crate::image_hill_top_v2::Point;
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use imageproc::drawing::{draw_filled_circle_mut, draw_polygon_mut};
+    use imageproc::point::Point as Vertex;
+
+    /// 画一个形状: 0圆形, 1三角形, 2十字, 3五角星
+    fn draw_shape(img: &mut DynamicImage, shape: usize, cx: f64, cy: f64, size: f64, angle: f64, color: Rgba<u8>) {
+        let r = size / 2.0;
+        let polygon: Vec<(f64, f64)> = match shape {
+            0 => {
+                draw_filled_circle_mut(img, (cx as i32, cy as i32), r as i32, color);
+                return;
+            }
+            1 => (0..3).map(|i| (r, i as f64 * 120.0 - 90.0)).collect(),
+            2 => {
+                let (a, b) = (r, r * 0.35);
+                [(b, -a), (b, -b), (a, -b), (a, b), (b, b), (b, a), (-b, a), (-b, b), (-a, b), (-a, -b), (-b, -b), (-b, -a)]
+                    .iter()
+                    .map(|&(x, y): &(f64, f64)| ((x * x + y * y).sqrt(), y.atan2(x).to_degrees()))
+                    .collect()
+            }
+            _ => (0..10).map(|i| (if i % 2 == 0 { r } else { r * 0.4 }, i as f64 * 36.0 - 90.0)).collect(),
+        };
+        let vertices: Vec<Vertex<i32>> = polygon.iter().map(|&(radius, theta)| {
+            let theta = (theta + angle).to_radians();
+            Vertex::new((cx + radius * theta.cos()).round() as i32, (cy + radius * theta.sin()).round() as i32)
+        }).collect();
+        draw_polygon_mut(img, &vertices, color);
+    }
+
+    fn make_hint(shapes: &[usize]) -> DynamicImage {
+        let width = 30 * shapes.len() as u32 + 10;
+        let mut hint = DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, 30, Rgba([255, 255, 255, 255])));
+        for (i, &shape) in shapes.iter().enumerate() {
+            draw_shape(&mut hint, shape, 20.0 + 30.0 * i as f64, 15.0, 20.0, 0.0, Rgba([30, 30, 30, 255]));
+        }
+        hint
+    }
+
+    fn point(x: usize, y: usize) -> Point {
+        Point::new(x, y, 0, 0.0, (x, y, x, y), 0)
+    }
+
+    #[test]
+    fn test_split_hint() {
+        let hint = make_hint(&[1, 0, 2, 3]);
+        let icons = split_hint(&hint).unwrap();
+        assert_eq!(icons.len(), 4);
+        for (i, (left, top, right, bottom)) in icons.iter().enumerate() {
+            let center = 20 + 30 * i as u32;
+            assert!(left.abs_diff(center - 10) <= 2 && right.abs_diff(center + 10) <= 2, "{:?}", icons);
+            assert!(*top >= 3 && *bottom <= 26);
+        }
+    }
+
+    /// 挑战图里画四个形状, 颜色、大小、角度都和提示图不一样, 返回(底图, 挑战图, 目标位置)
+    fn make_challenge(targets: &[(usize, f64, f64)]) -> (DynamicImage, DynamicImage, Vec<Point>) {
+        let background = image::open("./src/images/0.jpg").unwrap();
+        let mut challenge = background.clone();
+        for (i, &(shape, x, y)) in targets.iter().enumerate() {
+            draw_shape(&mut challenge, shape, x, y, 34.0, 12.0 * i as f64, Rgba([220, 60 + 40 * i as u8, 40, 255]));
+        }
+        let points = targets.iter().map(|&(_, x, y)| point(x as usize, y as usize)).collect();
+        (background, challenge, points)
+    }
+
+    fn positions(points: &[Point]) -> Vec<(usize, usize)> {
+        points.iter().map(|p| (p.x, p.y)).collect()
+    }
+
+    #[test]
+    fn test_order_by_hint() {
+        // 背景比较干净的位置, 不需要底图
+        let (_, challenge, points) = make_challenge(&[(0, 60.0, 50.0), (1, 150.0, 25.0), (2, 220.0, 45.0), (3, 30.0, 120.0)]);
+        let hint = make_hint(&[3, 0, 1]);
+        let ordered = order_by_hint(&hint, &challenge, None, &points, 44).unwrap();
+        assert_eq!(positions(&ordered), vec![(30, 120), (60, 50), (150, 25)]);
+        assert_eq!(ordered.iter().map(|p| p.rank).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        let too_many = make_hint(&[3, 0, 1, 2, 0]);
+        assert!(order_by_hint(&too_many, &challenge, None, &points, 44).is_err());
+    }
+
+    #[test]
+    fn test_order_by_hint_invalid_input() {
+        let (_, challenge, mut points) = make_challenge(&[(0, 60.0, 50.0), (1, 150.0, 25.0)]);
+        let hint = make_hint(&[1, 0]);
+        let empty = DynamicImage::new_rgba8(0, 0);
+        let err = order_by_hint(&hint, &empty, None, &points, 44).unwrap_err();
+        assert!(matches!(err, Error::EmptyInput(_)));
+        let err = order_by_hint(&hint, &challenge, Some(&empty), &points, 44).unwrap_err();
+        assert!(matches!(err, Error::EmptyInput(_)));
+
+        // 超出挑战图, 包括转成u32会截断的坐标
+        for x in [300, u32::MAX as usize + 10] {
+            points[1].x = x;
+            let err = order_by_hint(&hint, &challenge, None, &points, 44).unwrap_err();
+            assert!(matches!(err, Error::InvalidParameter(_)), "{}", x);
+        }
+        // 特别大的ch_size不会溢出
+        points[1].x = 150;
+        assert_eq!(order_by_hint(&hint, &challenge, None, &points, u32::MAX).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_order_by_hint_with_background() {
+        // 三角形画在楼上, 条纹背景会干扰边缘, 传底图之后用差值图比较
+        let (background, challenge, points) = make_challenge(&[(0, 60.0, 50.0), (1, 140.0, 100.0), (2, 220.0, 45.0), (3, 250.0, 110.0)]);
+        let hint = make_hint(&[1, 2, 3, 0]);
+        let ordered = order_by_hint(&hint, &challenge, Some(&background), &points, 44).unwrap();
+        assert_eq!(positions(&ordered), vec![(140, 100), (220, 45), (250, 110), (60, 50)]);
+    }
+
+    #[test]
+    fn test_solve_assignment() {
+        // 贪心会先选0-0, 最优解是0-1, 1-0
+        let similarity = vec![vec![0.9, 0.85], vec![0.8, 0.1]];
+        assert_eq!(solve_assignment(&similarity), vec![1, 0]);
+        let similarity = vec![vec![0.1, 0.2, 0.9], vec![0.7, 0.1, 0.3]];
+        assert_eq!(solve_assignment(&similarity), vec![2, 0]);
+    }
+}
@@ -0,0 +1,251 @@
+use std::str::FromStr;
+
+use image::Rgba;
+use lazy_static::lazy_static;
+
+use crate::error::{Error, Result};
+use crate::image_utils::{rgb_diff, MAX_RGB_DIFF};
+
+/// 计算差值图时两个像素点的颜色差异算法, 所有算法的结果都缩放到`0..=MAX_RGB_DIFF`,
+/// 这样金字塔、置信度之类基于`rgb_diff`量级的逻辑不需要区分
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum DiffMetric {
+    /// RGB三个通道差的绝对值之和, 也就是原来的`rgb_diff`
+    #[default]
+    L1,
+    /// RGB空间的欧氏距离
+    L2,
+    /// Lab空间的欧氏距离
+    Cie76,
+    /// CIEDE2000色差公式, 最接近人眼感知, 也最慢
+    Ciede2000,
+    /// 只比较亮度, 适合灰度图或者只有明暗变化的挑战图
+    Luma,
+    /// HSV空间, 色相差异权重最高, 对jpeg压缩带来的明暗噪声不敏感
+    HueWeighted,
+}
+
+impl FromStr for DiffMetric {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "l1" | "rgb" => Ok(DiffMetric::L1),
+            "l2" | "euclidean" => Ok(DiffMetric::L2),
+            "cie76" | "lab" => Ok(DiffMetric::Cie76),
+            "ciede2000" | "de2000" => Ok(DiffMetric::Ciede2000),
+            "luma" | "gray" => Ok(DiffMetric::Luma),
+            "hue" | "hsv" => Ok(DiffMetric::HueWeighted),
+            _ => Err(Error::InvalidParameter(format!("unknown diff metric: {}", s))),
+        }
+    }
+}
+
+/// Lab色差为100(纯黑和纯白之间)时对应`MAX_RGB_DIFF`, 超过的部分截断
+const DELTA_E_SCALE: f64 = MAX_RGB_DIFF as f64 / 100.0;
+
+impl DiffMetric {
+    /// 两个像素点的差异, 范围`0..=MAX_RGB_DIFF`, 忽略alpha通道
+    pub fn diff(self, left: Rgba<u8>, right: Rgba<u8>) -> i32 {
+        let value = match self {
+            DiffMetric::L1 => return rgb_diff(left, right),
+            DiffMetric::L2 => {
+                let sum: f64 = (0..3).map(|i| (left[i] as f64 - right[i] as f64).powi(2)).sum();
+                // 最大值是255 * sqrt(3), 乘上sqrt(3)和L1对齐
+                sum.sqrt() * 3f64.sqrt()
+            }
+            DiffMetric::Cie76 => {
+                let (l1, a1, b1) = srgb_to_lab(left);
+                let (l2, a2, b2) = srgb_to_lab(right);
+                ((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt() * DELTA_E_SCALE
+            }
+            DiffMetric::Ciede2000 => ciede2000(srgb_to_lab(left), srgb_to_lab(right)) * DELTA_E_SCALE,
+            DiffMetric::Luma => (luma(left) - luma(right)).abs() * 3.0,
+            DiffMetric::HueWeighted => hue_weighted(left, right),
+        };
+        (value.round() as i32).clamp(0, MAX_RGB_DIFF)
+    }
+}
+
+lazy_static! {
+    /// sRGB 8位值 -> 线性亮度的查找表
+    static ref SRGB_TO_LINEAR: [f64; 256] = {
+        let mut table = [0.0; 256];
+        for (i, val) in table.iter_mut().enumerate() {
+            let c = i as f64 / 255.0;
+            *val = if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
+        }
+        table
+    };
+}
+
+/// sRGB -> CIE Lab (D65白点)
+pub fn srgb_to_lab(pixel: Rgba<u8>) -> (f64, f64, f64) {
+    let r = SRGB_TO_LINEAR[pixel[0] as usize];
+    let g = SRGB_TO_LINEAR[pixel[1] as usize];
+    let b = SRGB_TO_LINEAR[pixel[2] as usize];
+
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+    let f = |t: f64| {
+        let delta: f64 = 6.0 / 29.0;
+        if t > delta.powi(3) { t.cbrt() } else { t / (3.0 * delta * delta) + 4.0 / 29.0 }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+/// CIEDE2000色差, 参考 Sharma, Wu, Dalal (2005)
+pub fn ciede2000(left: (f64, f64, f64), right: (f64, f64, f64)) -> f64 {
+    let (l1, a1, b1) = left;
+    let (l2, a2, b2) = right;
+    let pow25_7 = 25f64.powi(7);
+
+    let c_bar = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) / 2.0;
+    let g = 0.5 * (1.0 - (c_bar.powi(7) / (c_bar.powi(7) + pow25_7)).sqrt());
+    let a1p = (1.0 + g) * a1;
+    let a2p = (1.0 + g) * a2;
+    let c1p = (a1p * a1p + b1 * b1).sqrt();
+    let c2p = (a2p * a2p + b2 * b2).sqrt();
+    let hue = |b: f64, ap: f64| {
+        if b == 0.0 && ap == 0.0 { 0.0 } else { b.atan2(ap).to_degrees().rem_euclid(360.0) }
+    };
+    let h1p = hue(b1, a1p);
+    let h2p = hue(b2, a2p);
+
+    let delta_lp = l2 - l1;
+    let delta_cp = c2p - c1p;
+    let chroma_zero = c1p * c2p == 0.0;
+    let delta_hp = if chroma_zero {
+        0.0
+    } else {
+        let d = h2p - h1p;
+        if d > 180.0 { d - 360.0 } else if d < -180.0 { d + 360.0 } else { d }
+    };
+    let delta_big_hp = 2.0 * (c1p * c2p).sqrt() * (delta_hp / 2.0).to_radians().sin();
+
+    let l_bar_p = (l1 + l2) / 2.0;
+    let c_bar_p = (c1p + c2p) / 2.0;
+    let h_bar_p = if chroma_zero {
+        h1p + h2p
+    } else if (h1p - h2p).abs() <= 180.0 {
+        (h1p + h2p) / 2.0
+    } else if h1p + h2p < 360.0 {
+        (h1p + h2p + 360.0) / 2.0
+    } else {
+        (h1p + h2p - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (h_bar_p - 30.0).to_radians().cos() + 0.24 * (2.0 * h_bar_p).to_radians().cos()
+        + 0.32 * (3.0 * h_bar_p + 6.0).to_radians().cos() - 0.20 * (4.0 * h_bar_p - 63.0).to_radians().cos();
+    let delta_theta = 30.0 * (-((h_bar_p - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (c_bar_p.powi(7) / (c_bar_p.powi(7) + pow25_7)).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar_p - 50.0).powi(2) / (20.0 + (l_bar_p - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar_p;
+    let s_h = 1.0 + 0.015 * c_bar_p * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    let dl = delta_lp / s_l;
+    let dc = delta_cp / s_c;
+    let dh = delta_big_hp / s_h;
+    (dl * dl + dc * dc + dh * dh + r_t * dc * dh).sqrt()
+}
+
+/// ITU-R BT.601 亮度, 0..255
+fn luma(pixel: Rgba<u8>) -> f64 {
+    0.299 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.114 * pixel[2] as f64
+}
+
+/// RGB -> (色相角度, 饱和度0..1, 明度0..1)
+fn rgb_to_hsv(pixel: Rgba<u8>) -> (f64, f64, f64) {
+    let (r, g, b) = (pixel[0] as f64 / 255.0, pixel[1] as f64 / 255.0, pixel[2] as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    (hue, saturation, max)
+}
+
+/// 色相差占一半权重(相差90度以上算最大), 饱和度和明度各占四分之一。
+/// 色相只有在两个颜色都足够鲜艳的时候才有意义, 所以按两者较小的色度(饱和度x明度)打折
+fn hue_weighted(left: Rgba<u8>, right: Rgba<u8>) -> f64 {
+    let (h1, s1, v1) = rgb_to_hsv(left);
+    let (h2, s2, v2) = rgb_to_hsv(right);
+    let mut delta_h = (h1 - h2).abs();
+    if delta_h > 180.0 {
+        delta_h = 360.0 - delta_h;
+    }
+    let chroma = (s1 * v1).min(s2 * v2);
+    let score = 0.5 * (delta_h / 90.0).min(1.0) * chroma + 0.25 * (s1 - s2).abs() + 0.25 * (v1 - v2).abs();
+    score * MAX_RGB_DIFF as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color_diff::{ciede2000, srgb_to_lab, DiffMetric};
+    use crate::image_utils::MAX_RGB_DIFF;
+    use image::Rgba;
+
+    const ALL: [DiffMetric; 6] = [DiffMetric::L1, DiffMetric::L2, DiffMetric::Cie76,
+        DiffMetric::Ciede2000, DiffMetric::Luma, DiffMetric::HueWeighted];
+
+    #[test]
+    fn test_lab() {
+        let (l, a, b) = srgb_to_lab(Rgba([255, 255, 255, 255]));
+        assert!((l - 100.0).abs() < 0.01 && a.abs() < 0.01 && b.abs() < 0.01);
+        let (l, a, b) = srgb_to_lab(Rgba([255, 0, 0, 255]));
+        assert!((l - 53.24).abs() < 0.05 && (a - 80.09).abs() < 0.05 && (b - 67.20).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_ciede2000() {
+        // Sharma论文里的测试数据
+        let cases = [
+            ((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485), 2.0425),
+            ((50.0, -1.3802, -84.2814), (50.0, 0.0, -82.7485), 1.0000),
+            ((50.0, 2.5, 0.0), (73.0, 25.0, -18.0), 27.1492),
+            ((60.2574, -34.0099, 36.2677), (60.4626, -34.1751, 39.4387), 1.2644),
+            ((22.7233, 20.0904, -46.6940), (23.0331, 14.9730, -42.5619), 2.0373),
+        ];
+        for (left, right, expected) in cases {
+            let actual = ciede2000(left, right);
+            assert!((actual - expected).abs() < 1e-4, "{:?} {:?}: {} vs {}", left, right, actual, expected);
+            assert!((ciede2000(right, left) - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_metric_range() {
+        let black = Rgba([0, 0, 0, 255]);
+        let white = Rgba([255, 255, 255, 255]);
+        let red = Rgba([200, 30, 30, 255]);
+        for metric in ALL {
+            assert_eq!(metric.diff(red, red), 0);
+            let value = metric.diff(red, Rgba([30, 30, 200, 255]));
+            assert!(value > 0 && value <= MAX_RGB_DIFF, "{:?}", metric);
+            assert!(metric.diff(black, white) <= MAX_RGB_DIFF);
+        }
+        assert_eq!(DiffMetric::L1.diff(black, white), MAX_RGB_DIFF);
+        assert_eq!(DiffMetric::L2.diff(black, white), MAX_RGB_DIFF);
+        assert_eq!(DiffMetric::Luma.diff(black, white), MAX_RGB_DIFF);
+
+        // 同一个颜色变暗 vs 换一个色相, L1一样大, 色相权重的算法要认为后者差异更大
+        let darker = Rgba([160, 10, 10, 255]);
+        let orange = Rgba([200, 110, 30, 255]);
+        assert_eq!(DiffMetric::L1.diff(red, darker), DiffMetric::L1.diff(red, orange));
+        assert!(DiffMetric::HueWeighted.diff(red, darker) < DiffMetric::HueWeighted.diff(red, orange));
+        assert!("l3".parse::<DiffMetric>().is_err());
+        assert_eq!("ciede2000".parse::<DiffMetric>().unwrap(), DiffMetric::Ciede2000);
+    }
+}
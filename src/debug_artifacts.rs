@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use image::{DynamicImage, GenericImageView, GrayImage, ImageOutputFormat, Luma, Rgb, RgbImage};
+use rustc_serialize::json::{Json, ToJson};
+
+use crate::error::{Error, Result};
+use crate::image_utils::Grid;
+
+/// 排查识别失败用的中间结果: 每一项是一张归一化之后的PNG, 另外有一份JSON清单记录每张图的含义
+#[derive(Default)]
+pub struct DebugArtifacts {
+    pub(crate) artifacts: Vec<Artifact>,
+    /// 清单顶层的字段, 比如图片尺寸、参数、最终结果
+    summary: BTreeMap<String, Json>,
+}
+
+pub struct Artifact {
+    /// 文件名, 不带扩展名
+    pub name: String,
+    /// `diff`/`pyramid`/`suppression`/`short_curt_mountain`
+    pub kind: &'static str,
+    pub image: DynamicImage,
+    /// 归一化之前的最大值, 像素值 = 原始值 * 255 / max_value
+    pub max_value: u64,
+    /// 其他说明, 比如是第几个山峰、网格在原图里的位置
+    pub meta: BTreeMap<String, Json>,
+}
+
+/// 黑 -> 红 -> 黄 -> 白的热力图配色
+fn heat_color(value: u8) -> Rgb<u8> {
+    let v = value as u32 * 3;
+    let r = v.min(255);
+    let g = v.saturating_sub(255).min(255);
+    let b = v.saturating_sub(510).min(255);
+    Rgb([r as u8, g as u8, b as u8])
+}
+
+impl DebugArtifacts {
+    pub fn new() -> DebugArtifacts {
+        DebugArtifacts::default()
+    }
+
+    /// 按生成顺序排列的所有中间结果
+    pub fn artifacts(&self) -> &[Artifact] {
+        &self.artifacts
+    }
+
+    pub(crate) fn set_summary<T: ToJson>(&mut self, key: &str, value: T) {
+        self.summary.insert(key.to_string(), value.to_json());
+    }
+
+    /// 把网格按最大值归一化到0..255存成一张图, `heatmap`为true的时候用热力图配色, 否则是灰度图
+    pub(crate) fn push_grid<T: Copy + Into<u64>>(&mut self, name: String, kind: &'static str, grid: &Grid<T>,
+                                                 heatmap: bool, meta: Vec<(&str, Json)>) {
+        let (width, height) = (grid.width() as u32, grid.height() as u32);
+        let max_value = (0..grid.height()).flat_map(|y| grid.row(y).iter().map(|&v| v.into())).max().unwrap_or(0);
+        let level = |x: u32, y: u32| -> u8 {
+            if max_value == 0 {
+                return 0;
+            }
+            let value: u64 = grid[(x as usize, y as usize)].into();
+            (value as u128 * 255 / max_value as u128) as u8
+        };
+        let image = if heatmap {
+            DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| heat_color(level(x, y))))
+        } else {
+            DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| Luma([level(x, y)])))
+        };
+        let meta = meta.into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+        self.artifacts.push(Artifact { name, kind, image, max_value, meta });
+    }
+
+    /// JSON清单, 每张图对应`artifacts`里的一项
+    pub fn manifest(&self) -> String {
+        let artifacts: Vec<Json> = self.artifacts.iter().map(|artifact| {
+            let mut entry = artifact.meta.clone();
+            entry.insert("file".to_string(), format!("{}.png", artifact.name).to_json());
+            entry.insert("kind".to_string(), artifact.kind.to_json());
+            entry.insert("width".to_string(), artifact.image.width().to_json());
+            entry.insert("height".to_string(), artifact.image.height().to_json());
+            entry.insert("max_value".to_string(), artifact.max_value.to_json());
+            Json::Object(entry)
+        }).collect();
+        let mut manifest = self.summary.clone();
+        manifest.insert("artifacts".to_string(), Json::Array(artifacts));
+        Json::Object(manifest).pretty().to_string()
+    }
+
+    /// 每张图编码成PNG, 返回`(文件名, PNG数据)`
+    pub fn to_png(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        self.artifacts.iter().map(|artifact| {
+            let mut buf = vec![];
+            artifact.image.write_to(&mut buf, ImageOutputFormat::Png).map_err(Error::Encode)?;
+            Ok((format!("{}.png", artifact.name), buf))
+        }).collect()
+    }
+
+    /// 写到目录里, 目录不存在的话会创建, 清单文件名是`manifest.json`
+    pub fn write_to_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        for (file, png) in self.to_png()? {
+            fs::write(dir.join(file), png)?;
+        }
+        fs::write(dir.join("manifest.json"), self.manifest())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debug_artifacts::{heat_color, DebugArtifacts};
+    use crate::image_utils::Grid;
+    use image::GenericImageView;
+    use rustc_serialize::json::{Json, ToJson};
+
+    #[test]
+    fn test_push_grid() {
+        let mut artifacts = DebugArtifacts::new();
+        let grid = Grid::from_fn(4, 3, |x, y| (x + y * 4) as u64 * 10);
+        artifacts.push_grid("diff".to_string(), "diff", &grid, true, vec![]);
+        artifacts.push_grid("level_1".to_string(), "pyramid", &grid, false, vec![("level", 1u32.to_json())]);
+        artifacts.push_grid("empty".to_string(), "pyramid", &Grid::new(2, 2, 0u64), false, vec![]);
+        artifacts.set_summary("ch_size", 40u32);
+
+        let level = artifacts.artifacts[1].image.to_luma8();
+        assert_eq!(level.get_pixel(0, 0)[0], 0);
+        assert_eq!(level.get_pixel(3, 2)[0], 255);
+        assert_eq!(artifacts.artifacts[0].image.dimensions(), (4, 3));
+        assert_eq!(heat_color(255), image::Rgb([255, 255, 255]));
+
+        let manifest = Json::from_str(&artifacts.manifest()).unwrap();
+        assert_eq!(manifest["ch_size"], Json::U64(40));
+        let entries = manifest["artifacts"].as_array().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1]["file"], Json::String("level_1.png".to_string()));
+        assert_eq!(entries[1]["level"], Json::U64(1));
+        assert_eq!(entries[1]["max_value"], Json::U64(110));
+        assert_eq!(entries[2]["max_value"], Json::U64(0));
+
+        let pngs = artifacts.to_png().unwrap();
+        assert!(pngs.iter().all(|(_, png)| png.starts_with(b"\x89PNG")));
+    }
+}
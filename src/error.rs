@@ -0,0 +1,103 @@
+use thiserror::Error;
+
+/// crate内部统一的错误类型, 导出给Python时会映射成不同的异常类
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid base64 input: {0}")]
+    InvalidBase64(#[from] base64::DecodeError),
+
+    #[error("unsupported image data: {0}")]
+    UnsupportedImage(#[source] image::ImageError),
+
+    #[error("unsupported array: {0}")]
+    UnsupportedArray(String),
+
+    #[error("failed to encode image: {0}")]
+    Encode(#[source] image::ImageError),
+
+    #[error("image size mismatch: expected {expected:?}, got {actual:?}")]
+    SizeMismatch { expected: (u32, u32), actual: (u32, u32) },
+
+    #[error("empty input: {0}")]
+    EmptyInput(String),
+
+    #[error("invalid parameter: {0}")]
+    InvalidParameter(String),
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("corrupted data: {0}")]
+    Corrupted(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// 导出给Python的异常类和错误转换
+#[cfg(feature = "python")]
+mod python {
+    use pyo3::create_exception;
+    use pyo3::exceptions::{PyException, PyFileNotFoundError};
+    use pyo3::prelude::*;
+
+    use super::Error;
+
+    create_exception!(image_magic, ImageMagicError, PyException);
+    create_exception!(image_magic, InvalidBase64Error, ImageMagicError);
+    create_exception!(image_magic, UnsupportedImageError, ImageMagicError);
+    create_exception!(image_magic, SizeMismatchError, ImageMagicError);
+    create_exception!(image_magic, EmptyInputError, ImageMagicError);
+    create_exception!(image_magic, InvalidParameterError, ImageMagicError);
+    create_exception!(image_magic, NotFoundError, ImageMagicError);
+    create_exception!(image_magic, CorruptedError, ImageMagicError);
+
+    impl From<Error> for PyErr {
+        fn from(err: Error) -> PyErr {
+            let msg = err.to_string();
+            match err {
+                Error::InvalidBase64(_) => InvalidBase64Error::new_err(msg),
+                Error::UnsupportedImage(_) | Error::UnsupportedArray(_) => UnsupportedImageError::new_err(msg),
+                Error::SizeMismatch { .. } => SizeMismatchError::new_err(msg),
+                Error::EmptyInput(_) => EmptyInputError::new_err(msg),
+                Error::InvalidParameter(_) => InvalidParameterError::new_err(msg),
+                Error::NotFound(_) => NotFoundError::new_err(msg),
+                Error::Corrupted(_) => CorruptedError::new_err(msg),
+                // 和Python自己打开文件一样抛`FileNotFoundError`, `NotFoundError`只表示图库里没有匹配的背景
+                Error::Io(e) if e.kind() == std::io::ErrorKind::NotFound => PyFileNotFoundError::new_err(msg),
+                Error::Encode(_) | Error::Io(_) => ImageMagicError::new_err(msg),
+            }
+        }
+    }
+
+    /// 把所有异常类注册到`image_magic`模块里, 方便Python侧`except`
+    pub(crate) fn register(py: Python, m: &PyModule) -> PyResult<()> {
+        m.add("ImageMagicError", py.get_type::<ImageMagicError>())?;
+        m.add("InvalidBase64Error", py.get_type::<InvalidBase64Error>())?;
+        m.add("UnsupportedImageError", py.get_type::<UnsupportedImageError>())?;
+        m.add("SizeMismatchError", py.get_type::<SizeMismatchError>())?;
+        m.add("EmptyInputError", py.get_type::<EmptyInputError>())?;
+        m.add("InvalidParameterError", py.get_type::<InvalidParameterError>())?;
+        m.add("NotFoundError", py.get_type::<NotFoundError>())?;
+        m.add("CorruptedError", py.get_type::<CorruptedError>())?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "python")]
+pub(crate) use python::register;
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn test_error_message() {
+        let err = Error::SizeMismatch { expected: (10, 20), actual: (5, 20) };
+        assert_eq!(err.to_string(), "image size mismatch: expected (10, 20), got (5, 20)");
+        let err = Error::from(base64::decode("@@@").unwrap_err());
+        assert!(matches!(err, Error::InvalidBase64(_)));
+    }
+}
@@ -1,23 +1,166 @@
 use image::{Rgba, Pixel, DynamicImage, GenericImageView, GenericImage};
-use std::collections::{BTreeMap};
-use crate::image_utils::rgb_diff;
+use image::imageops::FilterType;
+#[cfg(feature = "python")]
+use pyo3::{prelude::*, PyObjectProtocol};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use crate::image_utils::{rgb_diff, Grid};
+use crate::error::{Error, Result};
 
-#[derive(Copy, Clone, Debug)]
-struct RGBA {
+/// 合成背景时每个像素点使用的统计量
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MergeStatistic {
+    /// 保留离均值最近的`ratio`比例的样本求平均
+    TrimmedMean { ratio: f64 },
+    /// 每个通道取中位数
+    Median,
+    /// 出现次数最多的颜色
+    Mode,
+    /// 截尾均值, 中间`ratio`比例之外的样本截断到边界值
+    WinsorizedMean { ratio: f64 },
+}
+
+impl MergeStatistic {
+    /// `FromStr`能解析的名字
+    pub fn name(&self) -> &'static str {
+        match self {
+            MergeStatistic::TrimmedMean { .. } => "trimmed_mean",
+            MergeStatistic::Median => "median",
+            MergeStatistic::Mode => "mode",
+            MergeStatistic::WinsorizedMean { .. } => "winsorized_mean",
+        }
+    }
+
+    fn ratio(&self) -> Option<f64> {
+        match self {
+            MergeStatistic::TrimmedMean { ratio } | MergeStatistic::WinsorizedMean { ratio } => Some(*ratio),
+            _ => None,
+        }
+    }
+
+    /// `mean`是所有样本的均值
+    fn merge(&self, samples: &[Rgba<u8>], mean: Rgba<u8>) -> Rgba<u8> {
+        match self {
+            MergeStatistic::TrimmedMean { ratio } => trimmed_mean(samples, mean, *ratio),
+            MergeStatistic::Median => median(samples),
+            MergeStatistic::Mode => mode(samples, mean),
+            MergeStatistic::WinsorizedMean { ratio } => winsorized_mean(samples, *ratio),
+        }
+    }
+}
+
+impl FromStr for MergeStatistic {
+    type Err = Error;
+
+    /// 只解析名字, `ratio`使用默认的0.85
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "trimmed_mean" | "trimmed" => Ok(MergeStatistic::TrimmedMean { ratio: DEFAULT_RATIO }),
+            "median" => Ok(MergeStatistic::Median),
+            "mode" => Ok(MergeStatistic::Mode),
+            "winsorized_mean" | "winsorized" => Ok(MergeStatistic::WinsorizedMean { ratio: DEFAULT_RATIO }),
+            _ => Err(Error::InvalidParameter(format!("unknown merge statistic: {}", s))),
+        }
+    }
+}
+
+const DEFAULT_RATIO: f64 = 0.85;
+
+/// 背景合成参数
+#[cfg_attr(feature = "python", pyclass(module = "image_magic"))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MergeOptions {
+    statistic: MergeStatistic,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions { statistic: MergeStatistic::TrimmedMean { ratio: DEFAULT_RATIO } }
+    }
+}
+
+impl MergeOptions {
+    pub fn new(statistic: MergeStatistic) -> Result<MergeOptions> {
+        if let Some(ratio) = statistic.ratio() {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                return Err(Error::InvalidParameter(format!("ratio must be in (0, 1], got {}", ratio)));
+            }
+        }
+        Ok(MergeOptions { statistic })
+    }
+
+    pub fn statistic(&self) -> MergeStatistic {
+        self.statistic
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl MergeOptions {
+    /// `statistic`: `trimmed_mean`/`median`/`mode`/`winsorized_mean`,
+    /// `ratio`只对`trimmed_mean`和`winsorized_mean`有效
+    #[new]
+    #[args(statistic = "\"trimmed_mean\"", ratio = "0.85")]
+    fn py_new(statistic: &str, ratio: f64) -> PyResult<MergeOptions> {
+        let statistic = match statistic.parse()? {
+            MergeStatistic::TrimmedMean { .. } => MergeStatistic::TrimmedMean { ratio },
+            MergeStatistic::WinsorizedMean { .. } => MergeStatistic::WinsorizedMean { ratio },
+            other => other,
+        };
+        Ok(MergeOptions::new(statistic)?)
+    }
+
+    #[getter(statistic)]
+    fn py_statistic(&self) -> &'static str {
+        self.statistic.name()
+    }
+
+    #[getter]
+    fn ratio(&self) -> Option<f64> {
+        self.statistic.ratio()
+    }
+}
+
+#[cfg(feature = "python")]
+#[pyproto]
+impl PyObjectProtocol for MergeOptions {
+    fn __repr__(&self) -> String {
+        match self.statistic.ratio() {
+            Some(ratio) => format!("MergeOptions(statistic='{}', ratio={})", self.statistic.name(), ratio),
+            None => format!("MergeOptions(statistic='{}')", self.statistic.name()),
+        }
+    }
+}
+
+/// 单个像素点的累加值, 平方和用来估计标准差
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct RGBA {
     r: u64,
     g: u64,
     b: u64,
     p: u64,
+    r2: u64,
+    g2: u64,
+    b2: u64,
+    p2: u64,
     total_record: u64,
 }
 
 impl RGBA {
+    /// 序列化之后的字节数
+    pub const ENCODED_LEN: usize = 9 * 8;
+
     pub fn new() -> RGBA {
         RGBA {
             r: 0,
             g: 0,
             b: 0,
             p: 0,
+            r2: 0,
+            g2: 0,
+            b2: 0,
+            p2: 0,
             total_record: 0,
         }
     }
@@ -28,6 +171,10 @@ impl RGBA {
         self.g += val[1] as u64;
         self.b += val[2] as u64;
         self.p += val[3] as u64;
+        self.r2 += val[0] as u64 * val[0] as u64;
+        self.g2 += val[1] as u64 * val[1] as u64;
+        self.b2 += val[2] as u64 * val[2] as u64;
+        self.p2 += val[3] as u64 * val[3] as u64;
     }
 
     pub fn avg_rgb(&self) -> Rgba<u8> {
@@ -38,9 +185,62 @@ impl RGBA {
             (self.p / self.total_record) as u8,
         )
     }
+
+    pub fn total_record(&self) -> u64 {
+        self.total_record
+    }
+
+    /// 每个通道的均值和标准差
+    pub fn mean_std(&self) -> [(f64, f64); 4] {
+        let n = self.total_record.max(1) as f64;
+        let channel = |sum: u64, sum2: u64| {
+            let mean = sum as f64 / n;
+            let variance = (sum2 as f64 / n - mean * mean).max(0.0);
+            (mean, variance.sqrt())
+        };
+        [
+            channel(self.r, self.r2),
+            channel(self.g, self.g2),
+            channel(self.b, self.b2),
+            channel(self.p, self.p2),
+        ]
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        for val in [self.r, self.g, self.b, self.p, self.r2, self.g2, self.b2, self.p2, self.total_record] {
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+    }
+
+    /// 每个通道的和不超过`255 * total_record`, 平方和不超过`255² * total_record`, 反序列化之后用来检查数据是否损坏
+    pub fn in_range(&self) -> bool {
+        let n = self.total_record as u128;
+        [self.r, self.g, self.b, self.p].iter().all(|&sum| sum as u128 <= 255 * n)
+            && [self.r2, self.g2, self.b2, self.p2].iter().all(|&sum2| sum2 as u128 <= 255 * 255 * n)
+    }
+
+    /// `input`的长度必须是`ENCODED_LEN`
+    pub fn decode(input: &[u8]) -> RGBA {
+        let mut vals = input.chunks_exact(8).map(|c| u64::from_le_bytes(c.try_into().unwrap()));
+        let mut next = || vals.next().unwrap();
+        RGBA {
+            r: next(),
+            g: next(),
+            b: next(),
+            p: next(),
+            r2: next(),
+            g2: next(),
+            b2: next(),
+            p2: next(),
+            total_record: next(),
+        }
+    }
 }
 
-pub(crate) fn avg(input: &Vec<DynamicImage>) -> DynamicImage {
+pub(crate) fn avg(input: &[DynamicImage], options: &MergeOptions) -> Result<DynamicImage> {
+    if input.is_empty() {
+        return Err(Error::EmptyInput("no image to merge".to_string()));
+    }
     let mut width_total: u64 = 0;
     let mut height_total: u64 = 0;
     for img in input {
@@ -51,82 +251,176 @@ pub(crate) fn avg(input: &Vec<DynamicImage>) -> DynamicImage {
     let height = (height_total / input.len() as u64) as u32;
     // println!("width = {}, height = {}", width, height);
 
-    let mut points = vec![vec![RGBA::new(); height as usize]; width as usize];
-
-    for img in input {
-        let mut fixed = img.clone();
+    // 尺寸不一致的图片统一缩放到平均尺寸, 否则后面按坐标取像素会越界
+    let input: Vec<DynamicImage> = input.iter().map(|img| {
         if (img.width() != width) || (img.height() != height) {
-            fixed = fixed.thumbnail(width, height);
+            img.resize_exact(width, height, FilterType::Triangle)
+        } else {
+            img.clone()
         }
+    }).collect();
+
+    // 每个像素点互不依赖, 开启`parallel`特性时会多线程计算
+    let pixels = Grid::from_fn(width as usize, height as usize, |i, j| {
+        let samples: Vec<Rgba<u8>> = input.iter().map(|img| img.get_pixel(i as u32, j as u32)).collect();
+        let mut point = RGBA::new();
+        for val in &samples {
+            point.set_val(*val);
+        }
+        options.statistic.merge(&samples, point.avg_rgb())
+    });
+
+    let mut output: DynamicImage = DynamicImage::new_rgba8(width, height);
+
+    for j in 0..height {
         for i in 0..width {
-            for j in 0..height {
-                let val = fixed.get_pixel(i as u32, j as u32);
-                points[i as usize][j as usize].set_val(val);
-            }
+            output.put_pixel(i, j, pixels[(i as usize, j as usize)]);
         }
     }
+    Ok(output)
+}
 
-    let mut first_avg_img = vec![vec![Rgba::from([0, 0, 0, 0]); height as usize]; width as usize];
-
-    for i in 0..width {
-        for j in 0..height {
-            first_avg_img[i as usize][j as usize] = points[i as usize][j as usize].avg_rgb();
+/// 保留离均值最近的`ratio`比例的样本求平均
+fn trimmed_mean(samples: &[Rgba<u8>], mean: Rgba<u8>, ratio: f64) -> Rgba<u8> {
+    let mut top_point: BTreeMap<u64, Rgba<u8>> = BTreeMap::new();
+    for (index, val) in samples.iter().enumerate() {
+        let rgb_diff = rgb_diff(*val, mean);
+        top_point.insert(((rgb_diff as u64) << 32) + index as u64, *val);
+    }
+    let avg_point_size = (samples.len() as f64 * ratio) as u32;
+    let mut avg_point_index = 0;
+    let mut rgba = RGBA::new();
+    for key in top_point.keys() {
+        rgba.set_val(*top_point.get(key).unwrap());
+        avg_point_index += 1;
+        if avg_point_index >= avg_point_size {
+            break;
         }
     }
+    rgba.avg_rgb()
+}
 
-    let mut output: DynamicImage = DynamicImage::new_rgba8(width as u32, height as u32);
+/// 每个通道单独取中位数, 偶数个样本取中间两个的平均
+fn median(samples: &[Rgba<u8>]) -> Rgba<u8> {
+    let mut out = [0u8; 4];
+    let mut channel = Vec::with_capacity(samples.len());
+    for (c, val) in out.iter_mut().enumerate() {
+        channel.clear();
+        channel.extend(samples.iter().map(|p| p[c]));
+        channel.sort_unstable();
+        let mid = channel.len() / 2;
+        *val = if channel.len() % 2 == 1 {
+            channel[mid]
+        } else {
+            ((channel[mid - 1] as u16 + channel[mid] as u16) / 2) as u8
+        };
+    }
+    Rgba(out)
+}
 
-    for i in 0..width {
-        for j in 0..height {
-            let mut top_point: BTreeMap<u64, Rgba<u8>> = BTreeMap::new();
-            let mut index = 0;
-            for img in input {
-                let rgb_diff = rgb_diff(img.get_pixel(i, j), first_avg_img[i as usize][j as usize]);
-                top_point.insert((((rgb_diff as u64) << 32) + index) as u64, img.get_pixel(i, j));
-                index += 1;
-            }
-            let avg_point_size = (input.len() as f64 * 0.85) as u32;
-            let mut avg_point_index = 0;
-            let mut rgba = RGBA::new();
-            for key in top_point.keys() {
-                rgba.set_val(*top_point.get(key).unwrap());
-                avg_point_index += 1;
-                if avg_point_index >= avg_point_size {
-                    break;
-                }
-            }
-            output.put_pixel(i, j, rgba.avg_rgb());
-        }
+/// 出现次数最多的颜色, 次数一样的取离均值近的
+fn mode(samples: &[Rgba<u8>], mean: Rgba<u8>) -> Rgba<u8> {
+    let mut counter: HashMap<Rgba<u8>, usize> = HashMap::new();
+    for val in samples {
+        *counter.entry(*val).or_insert(0) += 1;
     }
-    output
+    counter.into_iter()
+        .max_by(|(l, l_count), (r, r_count)| {
+            l_count.cmp(r_count)
+                .then_with(|| rgb_diff(*r, mean).cmp(&rgb_diff(*l, mean)))
+                .then_with(|| r.0.cmp(&l.0))
+        })
+        .map(|(val, _)| val)
+        .unwrap()
 }
 
+/// 每个通道两头各`(1 - ratio) / 2`的样本被截断到边界值之后再求平均
+fn winsorized_mean(samples: &[Rgba<u8>], ratio: f64) -> Rgba<u8> {
+    let mut out = [0u8; 4];
+    let mut channel = Vec::with_capacity(samples.len());
+    let cut = (samples.len() as f64 * (1.0 - ratio) / 2.0) as usize;
+    for (c, val) in out.iter_mut().enumerate() {
+        channel.clear();
+        channel.extend(samples.iter().map(|p| p[c] as u64));
+        channel.sort_unstable();
+        let low = channel[cut];
+        let high = channel[channel.len() - 1 - cut];
+        let total: u64 = channel.iter().map(|v| (*v).clamp(low, high)).sum();
+        *val = (total / channel.len() as u64) as u8;
+    }
+    Rgba(out)
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::image_avg_merger::{RGBA, avg};
-    use image::{Rgba, Pixel};
+    use crate::error::Error;
+    use crate::image_avg_merger::{RGBA, MergeOptions, MergeStatistic, avg, median, mode, winsorized_mean};
+    use image::{Rgba, Pixel, GenericImageView};
 
     #[test]
     fn test_rgba() {
         let mut rgba = RGBA::new();
         rgba.set_val(Rgba::from_channels(123, 123, 0, 0));
-        println!("{}", rgba.r)
+        println!("{}", rgba.r);
+        rgba.set_val(Rgba::from_channels(121, 125, 0, 0));
+        let [(r_mean, r_std), (g_mean, g_std), _, _] = rgba.mean_std();
+        assert_eq!((r_mean, r_std), (122.0, 1.0));
+        assert_eq!((g_mean, g_std), (124.0, 1.0));
+
+        let mut buf = vec![];
+        rgba.encode(&mut buf);
+        assert_eq!(buf.len(), RGBA::ENCODED_LEN);
+        assert_eq!(RGBA::decode(&buf), rgba);
     }
 
 
     #[test]
     fn test_avg() {
-        let mut input = vec![];
+        let input = crate::test_util::load_images();
+        let output = avg(&input, &MergeOptions::default()).unwrap();
+        let dir = crate::test_util::temp_dir();
+        output.save(dir.path().join("output.jpg")).unwrap();
+    }
+
+    #[test]
+    fn test_avg_mixed_size() {
         let img = image::open("./src/images/0.jpg").unwrap();
-        input.push(img);
-        let img = image::open("./src/images/1.jpg").unwrap();
-        input.push(img);
-        let img = image::open("./src/images/2.jpg").unwrap();
-        input.push(img);
-        let img = image::open("./src/images/3.jpg").unwrap();
-        input.push(img);
-        let output = avg(&input);
-        output.save("./src/output.jpg").unwrap();
+        let input = vec![img.clone(), img.resize_exact(200, 120, image::imageops::FilterType::Triangle)];
+        let output = avg(&input, &MergeOptions::default()).unwrap();
+        assert_eq!((output.width(), output.height()), ((img.width() + 200) / 2, (img.height() + 120) / 2));
+    }
+
+    #[test]
+    fn test_statistics() {
+        let samples = [
+            Rgba::from_channels(10, 10, 10, 255),
+            Rgba::from_channels(12, 12, 12, 255),
+            Rgba::from_channels(12, 12, 12, 255),
+            Rgba::from_channels(14, 14, 14, 255),
+            Rgba::from_channels(250, 0, 250, 255),
+        ];
+        assert_eq!(median(&samples), Rgba::from_channels(12, 12, 12, 255));
+        assert_eq!(mode(&samples, Rgba::from_channels(60, 10, 60, 255)), Rgba::from_channels(12, 12, 12, 255));
+        // 5个样本保留60%, 两头各截断一个
+        assert_eq!(winsorized_mean(&samples, 0.6), Rgba::from_channels(12, 11, 12, 255));
+    }
+
+    #[test]
+    fn test_avg_with_options() {
+        let input = crate::test_util::load_images();
+        let default = avg(&input, &MergeOptions::default()).unwrap();
+        let trimmed = avg(&input, &MergeOptions::new(MergeStatistic::TrimmedMean { ratio: 0.85 }).unwrap()).unwrap();
+        assert_eq!(default.to_rgba8(), trimmed.to_rgba8());
+        for statistic in [MergeStatistic::Median, MergeStatistic::Mode, MergeStatistic::WinsorizedMean { ratio: 0.5 }] {
+            let output = avg(&input, &MergeOptions::new(statistic).unwrap()).unwrap();
+            assert_eq!(output.dimensions(), default.dimensions());
+        }
+        assert!(MergeOptions::new(MergeStatistic::TrimmedMean { ratio: 0.0 }).is_err());
+        assert!(MergeOptions::new(MergeStatistic::WinsorizedMean { ratio: 1.5 }).is_err());
+    }
+
+    #[test]
+    fn test_avg_empty() {
+        assert!(matches!(avg(&[], &MergeOptions::default()), Err(Error::EmptyInput(_))));
     }
 }
\ No newline at end of file
@@ -0,0 +1,160 @@
+use std::cmp::Reverse;
+
+use image::DynamicImage;
+use img_hash::{HasherConfig, HashAlg, ImageHash};
+#[cfg(feature = "python")]
+use pyo3::{prelude::*, PyObjectProtocol};
+
+use crate::error::{Error, Result};
+use crate::image_avg_merger::{avg, MergeOptions};
+#[cfg(feature = "python")]
+use crate::image_utils::encode_png_b64;
+
+/// 聚类默认的汉明距离阈值, 64位的梯度哈希
+pub const DEFAULT_MAX_DISTANCE: u32 = 10;
+
+/// 同一张底图合成出来的背景
+#[cfg_attr(feature = "python", pyclass(module = "image_magic"))]
+#[derive(Clone, Debug)]
+pub struct BackgroundGroup {
+    background: DynamicImage,
+    /// 属于这一组的图片在输入里的下标
+    members: Vec<usize>,
+}
+
+impl BackgroundGroup {
+    pub fn background(&self) -> &DynamicImage {
+        &self.background
+    }
+
+    pub fn members(&self) -> &[usize] {
+        &self.members
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl BackgroundGroup {
+    /// 合成的背景图, png格式的base64字符串
+    #[getter(background)]
+    fn py_background(&self) -> PyResult<String> {
+        Ok(encode_png_b64(&self.background)?)
+    }
+
+    #[getter(members)]
+    fn py_members(&self) -> Vec<usize> {
+        self.members.clone()
+    }
+
+    #[getter]
+    fn count(&self) -> usize {
+        self.members.len()
+    }
+}
+
+#[cfg(feature = "python")]
+#[pyproto]
+impl PyObjectProtocol for BackgroundGroup {
+    fn __repr__(&self) -> String {
+        format!("BackgroundGroup(count={}, members={:?})", self.members.len(), self.members)
+    }
+}
+
+pub(crate) fn perceptual_hash(img: &DynamicImage) -> ImageHash {
+    HasherConfig::new().hash_alg(HashAlg::Gradient).to_hasher().hash_image(img)
+}
+
+fn find(parent: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    // 路径压缩
+    let mut cur = i;
+    while parent[cur] != root {
+        let next = parent[cur];
+        parent[cur] = root;
+        cur = next;
+    }
+    root
+}
+
+/// 按感知哈希的汉明距离把图片分组, 距离不超过`max_distance`的图片连到一起(单链接)
+///
+/// 返回每一组的下标, 按组的大小从大到小排列
+pub(crate) fn cluster(input: &[DynamicImage], max_distance: u32) -> Vec<Vec<usize>> {
+    let hashes: Vec<ImageHash> = input.iter().map(perceptual_hash).collect();
+    let mut parent: Vec<usize> = (0..input.len()).collect();
+    for i in 0..hashes.len() {
+        for j in (i + 1)..hashes.len() {
+            if hashes[i].dist(&hashes[j]) <= max_distance {
+                let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                if a != b {
+                    parent[a.max(b)] = a.min(b);
+                }
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut group_of_root = vec![usize::MAX; input.len()];
+    for i in 0..input.len() {
+        let root = find(&mut parent, i);
+        if group_of_root[root] == usize::MAX {
+            group_of_root[root] = groups.len();
+            groups.push(vec![]);
+        }
+        groups[group_of_root[root]].push(i);
+    }
+    // 稳定排序, 一样大的组保持第一次出现的顺序
+    groups.sort_by_key(|group| Reverse(group.len()));
+    groups
+}
+
+/// 先按底图分组, 再对每一组分别合成背景
+pub(crate) fn group_avg(input: &[DynamicImage], max_distance: u32, options: &MergeOptions) -> Result<Vec<BackgroundGroup>> {
+    if input.is_empty() {
+        return Err(Error::EmptyInput("no image to merge".to_string()));
+    }
+    cluster(input, max_distance).into_iter().map(|members| {
+        let images: Vec<DynamicImage> = members.iter().map(|i| input[*i].clone()).collect();
+        Ok(BackgroundGroup { background: avg(&images, options)?, members })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::image_avg_merger::MergeOptions;
+    use crate::image_cluster::{cluster, group_avg, DEFAULT_MAX_DISTANCE};
+    use crate::test_util::load_images;
+    use image::{DynamicImage, GenericImageView};
+
+    fn mixed_images() -> Vec<DynamicImage> {
+        let mut input = vec![];
+        for img in load_images() {
+            // 翻转之后当成另外一张底图
+            input.push(img.fliph());
+            input.push(img);
+        }
+        input
+    }
+
+    #[test]
+    fn test_cluster() {
+        let groups = cluster(&mixed_images(), DEFAULT_MAX_DISTANCE);
+        assert_eq!(groups, vec![vec![0, 2, 4, 6], vec![1, 3, 5, 7]]);
+        // 阈值为0的时候每张图片都不一样
+        assert_eq!(cluster(&mixed_images(), 0).len(), 8);
+    }
+
+    #[test]
+    fn test_group_avg() {
+        let groups = group_avg(&mixed_images(), DEFAULT_MAX_DISTANCE, &MergeOptions::default()).unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].members().len(), 4);
+        assert_eq!(groups[1].members(), &[1, 3, 5, 7]);
+        assert_eq!(groups[0].background().dimensions(), (300, 150));
+        assert!(matches!(group_avg(&[], DEFAULT_MAX_DISTANCE, &MergeOptions::default()), Err(Error::EmptyInput(_))));
+    }
+}
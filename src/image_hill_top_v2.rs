@@ -1,26 +1,94 @@
-use pyo3::{prelude::*};
+#[cfg(feature = "python")]
+use pyo3::{prelude::*, basic::CompareOp, PyIterProtocol, PyObjectProtocol};
+#[cfg(feature = "python")]
+use pyo3::types::{PyDict, PyTuple, PyType};
 
+#[cfg(feature = "python")]
+use std::collections::hash_map::DefaultHasher;
 use std::f64::consts::{SQRT_2, PI};
-use image::{DynamicImage, GenericImageView, ImageBuffer, GrayImage};
-use crate::image_utils::rgb_diff;
+#[cfg(feature = "python")]
+use std::hash::{Hash, Hasher};
+use image::{DynamicImage, GenericImageView};
+use image::imageops::FilterType;
+use crate::color_diff::DiffMetric;
+use crate::debug_artifacts::DebugArtifacts;
+use crate::image_utils::{Grid, IntegralImage, MAX_RGB_DIFF};
+use crate::error::{Error, Result};
 use std::cmp::{min, max};
+use std::collections::BTreeMap;
+use rustc_serialize::json::{Json, ToJson};
+use std::str::FromStr;
 
-#[pyclass]
-#[derive(Copy, Clone, Debug)]
+/// (left, top, right, bottom), 包含右下角
+pub type BoundingBox = (usize, usize, usize, usize);
+
+/// 找到的一个目标, 结果按权重从高到低排列
+///
+/// 字段都可以改, `annotate`和`order_by_hint`会检查坐标和`bbox`是否在挑战图里, 不合法的返回错误
+#[cfg_attr(feature = "python", pyclass(module = "image_magic"))]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Point {
-    x: usize,
-    y: usize,
-    weight: usize,
+    /// 目标中心在挑战图里的坐标
+    pub x: usize,
+    pub y: usize,
+    /// 窗口内差值的加权和, 越大差异越明显
+    pub weight: usize,
+    /// 权重相对于窗口内理论最大值的比例, 0..1
+    pub confidence: f64,
+    /// 以坐标为中心、`ch_size`为边长的框, 已经裁剪到挑战图范围内
+    pub bbox: BoundingBox,
+    /// 结果里的名次, 从1开始
+    pub rank: usize,
+    /// 亚像素精度的坐标, 没有开启细化的时候和`x`/`y`相同
+    pub fx: f64,
+    pub fy: f64,
 }
 
 impl Point {
-    fn new(x: usize, y: usize, weight: usize) -> Point {
-        Point { x, y, weight }
+    pub(crate) fn new(x: usize, y: usize, weight: usize, confidence: f64, bbox: BoundingBox, rank: usize) -> Point {
+        Point { x, y, weight, confidence, bbox, rank, fx: x as f64, fy: y as f64 }
+    }
+
+    pub(crate) fn with_subpixel(mut self, fx: f64, fy: f64) -> Point {
+        self.fx = fx;
+        self.fy = fy;
+        self
+    }
+
+    #[cfg(feature = "python")]
+    fn state(&self) -> (usize, usize, usize, f64, BoundingBox, usize, f64, f64) {
+        (self.x, self.y, self.weight, self.confidence, self.bbox, self.rank, self.fx, self.fy)
+    }
+}
+
+impl ToJson for Point {
+    fn to_json(&self) -> Json {
+        let mut entry = BTreeMap::new();
+        entry.insert("rank".to_string(), self.rank.to_json());
+        entry.insert("x".to_string(), self.x.to_json());
+        entry.insert("y".to_string(), self.y.to_json());
+        entry.insert("fx".to_string(), self.fx.to_json());
+        entry.insert("fy".to_string(), self.fy.to_json());
+        entry.insert("weight".to_string(), self.weight.to_json());
+        entry.insert("confidence".to_string(), self.confidence.to_json());
+        let (left, top, right, bottom) = self.bbox;
+        entry.insert("bbox".to_string(), vec![left, top, right, bottom].to_json());
+        Json::Object(entry)
     }
 }
 
+#[cfg(feature = "python")]
 #[pymethods]
 impl Point {
+    #[new]
+    #[args(weight = "0", confidence = "0.0", bbox = "None", rank = "0", fx = "None", fy = "None")]
+    #[allow(clippy::too_many_arguments)]
+    fn py_new(x: usize, y: usize, weight: usize, confidence: f64, bbox: Option<BoundingBox>, rank: usize,
+              fx: Option<f64>, fy: Option<f64>) -> Point {
+        Point::new(x, y, weight, confidence, bbox.unwrap_or((x, y, x, y)), rank)
+            .with_subpixel(fx.unwrap_or(x as f64), fy.unwrap_or(y as f64))
+    }
+
     pub fn get_x(&self) -> PyResult<u32> {
         PyResult::Ok(self.x as u32)
     }
@@ -28,6 +96,154 @@ impl Point {
     pub fn get_y(&self) -> PyResult<u32> {
         PyResult::Ok(self.y as u32)
     }
+
+    #[getter]
+    fn x(&self) -> usize {
+        self.x
+    }
+
+    #[getter]
+    fn y(&self) -> usize {
+        self.y
+    }
+
+    #[getter]
+    fn weight(&self) -> usize {
+        self.weight
+    }
+
+    #[getter]
+    fn confidence(&self) -> f64 {
+        self.confidence
+    }
+
+    #[getter]
+    fn bbox(&self) -> BoundingBox {
+        self.bbox
+    }
+
+    #[getter]
+    fn rank(&self) -> usize {
+        self.rank
+    }
+
+    #[getter]
+    fn fx(&self) -> f64 {
+        self.fx
+    }
+
+    #[getter]
+    fn fy(&self) -> f64 {
+        self.fy
+    }
+
+    #[allow(clippy::wrong_self_convention)]
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let dict = PyDict::new(py);
+        dict.set_item("x", self.x)?;
+        dict.set_item("y", self.y)?;
+        dict.set_item("weight", self.weight)?;
+        dict.set_item("confidence", self.confidence)?;
+        dict.set_item("bbox", self.bbox)?;
+        dict.set_item("rank", self.rank)?;
+        dict.set_item("fx", self.fx)?;
+        dict.set_item("fy", self.fy)?;
+        Ok(dict)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn __reduce__<'py>(&self, py: Python<'py>) -> (&'py PyType, (usize, usize, usize, f64, BoundingBox, usize, f64, f64)) {
+        (py.get_type::<Point>(), self.state())
+    }
+}
+
+#[cfg(feature = "python")]
+#[pyproto]
+impl PyObjectProtocol for Point {
+    fn __repr__(&self) -> String {
+        format!("Point(x={}, y={}, weight={}, confidence={:.4}, bbox={:?}, rank={}, fx={:.2}, fy={:.2})",
+                self.x, self.y, self.weight, self.confidence, self.bbox, self.rank, self.fx, self.fy)
+    }
+
+    fn __richcmp__(&self, other: PyRef<Point>, op: CompareOp) -> PyObject {
+        let py = other.py();
+        match op {
+            CompareOp::Eq => (*self == *other).into_py(py),
+            CompareOp::Ne => (*self != *other).into_py(py),
+            _ => py.NotImplemented(),
+        }
+    }
+
+    fn __hash__(&self) -> isize {
+        let mut hasher = DefaultHasher::new();
+        let (x, y, weight, confidence, bbox, rank, fx, fy) = self.state();
+        (x, y, weight, confidence.to_bits(), bbox, rank, fx.to_bits(), fy.to_bits()).hash(&mut hasher);
+        hasher.finish() as isize
+    }
+}
+
+#[cfg(feature = "python")]
+#[pyproto]
+impl PyIterProtocol for Point {
+    /// 支持 `x, y = point`
+    fn __iter__(slf: PyRef<Self>) -> PyResult<PyObject> {
+        let py = slf.py();
+        let pair = PyTuple::new(py, [slf.x, slf.y]);
+        Ok(pair.call_method0("__iter__")?.into())
+    }
+}
+
+/// 底图和挑战图尺寸不一致时的处理方式
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum SizeStrategy {
+    /// 底图直接缩放到挑战图的尺寸
+    #[default]
+    Resize,
+    /// 从底图左上角裁出挑战图大小的区域
+    CropTopLeft,
+    /// 从底图中心裁出挑战图大小的区域
+    CenterCrop,
+    /// 尺寸不一致直接报错
+    Reject,
+}
+
+impl FromStr for SizeStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "resize" => Ok(SizeStrategy::Resize),
+            "crop" | "top_left" => Ok(SizeStrategy::CropTopLeft),
+            "center" | "center_crop" => Ok(SizeStrategy::CenterCrop),
+            "reject" => Ok(SizeStrategy::Reject),
+            _ => Err(Error::InvalidParameter(format!("unknown size strategy: {}", s))),
+        }
+    }
+}
+
+impl SizeStrategy {
+    /// 按策略把底图对齐到`width` x `height`
+    fn align(self, bg_image: &DynamicImage, width: u32, height: u32) -> Result<DynamicImage> {
+        if bg_image.dimensions() == (width, height) {
+            return Ok(bg_image.clone());
+        }
+        let mismatch = || Error::SizeMismatch {
+            expected: (width, height),
+            actual: bg_image.dimensions(),
+        };
+        match self {
+            SizeStrategy::Resize => Ok(bg_image.resize_exact(width, height, FilterType::Triangle)),
+            SizeStrategy::Reject => Err(mismatch()),
+            // 裁剪只能在底图比挑战图大的时候用
+            _ if bg_image.width() < width || bg_image.height() < height => Err(mismatch()),
+            SizeStrategy::CropTopLeft => Ok(bg_image.crop_imm(0, 0, width, height)),
+            SizeStrategy::CenterCrop => {
+                let x = (bg_image.width() - width) / 2;
+                let y = (bg_image.height() - height) / 2;
+                Ok(bg_image.crop_imm(x, y, width, height))
+            }
+        }
+    }
 }
 
 pub struct HilltopParamAndResult {
@@ -35,7 +251,96 @@ pub struct HilltopParamAndResult {
     challenge_image: DynamicImage,
     ch_size: u32,
     top_n: usize,
-    avg_diff: u32,
+    size_strategy: SizeStrategy,
+    diff_metric: DiffMetric,
+    ignore_rects: Vec<BoundingBox>,
+    ignore_mask: Option<DynamicImage>,
+    roi: Option<BoundingBox>,
+    peak_options: PeakOptions,
+}
+
+/// 自动判断目标个数: 设置了任意一个阈值之后`top_n`只是上限,
+/// 下一个山峰的权重低于阈值就停止, 权重为0的山峰也不再返回
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct StopRule {
+    /// 权重低于第一个山峰的这个比例就停止
+    pub min_peak_ratio: Option<f64>,
+    /// 权重低于"整张图都是平均差值"时窗口权重的这个倍数就停止
+    pub min_avg_ratio: Option<f64>,
+}
+
+/// 抑制区域的形状
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum SuppressionShape {
+    /// 按欧氏距离, 圆形区域
+    #[default]
+    Circle,
+    /// 按横纵坐标差的较大值, 正方形区域
+    Rect,
+}
+
+impl FromStr for SuppressionShape {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "circle" | "circular" => Ok(SuppressionShape::Circle),
+            "rect" | "rectangle" | "square" => Ok(SuppressionShape::Rect),
+            _ => Err(Error::InvalidParameter(format!("unknown suppression shape: {}", s))),
+        }
+    }
+}
+
+impl SuppressionShape {
+    /// 偏移`(dx, dy)`是否在距离`distance`以内(不含边界)
+    fn within(self, dx: i64, dy: i64, distance: usize) -> bool {
+        let distance = distance as i64;
+        match self {
+            SuppressionShape::Circle => dx * dx + dy * dy < distance * distance,
+            SuppressionShape::Rect => dx.abs() < distance && dy.abs() < distance,
+        }
+    }
+}
+
+/// 非极大值抑制: 返回的任意两个点之间的距离都不小于`min_distance`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Suppression {
+    pub min_distance: usize,
+    pub shape: SuppressionShape,
+}
+
+/// `find_peaks`里除了差值图以外的可选项
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PeakOptions {
+    pub stop_rule: StopRule,
+    pub suppression: Option<Suppression>,
+    /// 对每个结果做亚像素细化
+    pub subpixel: bool,
+}
+
+impl StopRule {
+    fn is_enabled(&self) -> bool {
+        self.min_peak_ratio.is_some() || self.min_avg_ratio.is_some()
+    }
+
+    fn validate(&self) -> Result<()> {
+        for ratio in [self.min_peak_ratio, self.min_avg_ratio].into_iter().flatten() {
+            if !ratio.is_finite() || ratio < 0.0 {
+                return Err(Error::InvalidParameter(format!("stop ratio must be a non-negative number, got {}", ratio)));
+            }
+        }
+        Ok(())
+    }
+
+    /// `first`: 第一个山峰的权重, `avg_weight`: 平均差值对应的窗口权重
+    fn should_stop(&self, weight: u64, first: u64, avg_weight: f64) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        weight == 0
+            || self.min_peak_ratio.is_some_and(|ratio| (weight as f64) < first as f64 * ratio)
+            || self.min_avg_ratio.is_some_and(|ratio| (weight as f64) < avg_weight * ratio)
+    }
 }
 
 impl HilltopParamAndResult {
@@ -46,8 +351,104 @@ impl HilltopParamAndResult {
             challenge_image,
             ch_size,
             top_n,
-            avg_diff: 0,
+            size_strategy: SizeStrategy::default(),
+            diff_metric: DiffMetric::default(),
+            ignore_rects: vec![],
+            ignore_mask: None,
+            roi: None,
+            peak_options: PeakOptions::default(),
+        }
+    }
+
+    pub fn with_size_strategy(mut self, size_strategy: SizeStrategy) -> HilltopParamAndResult {
+        self.size_strategy = size_strategy;
+        self
+    }
+
+    /// 计算差值图用的颜色差异算法, 默认`DiffMetric::L1`
+    pub fn with_diff_metric(mut self, diff_metric: DiffMetric) -> HilltopParamAndResult {
+        self.diff_metric = diff_metric;
+        self
+    }
+
+    /// 忽略挑战图里的一块区域(水印、刷新按钮之类), 可以调用多次
+    pub fn with_ignore_rect(mut self, rect: BoundingBox) -> HilltopParamAndResult {
+        self.ignore_rects.push(rect);
+        self
+    }
+
+    /// 忽略掩码, 尺寸必须和挑战图一致, 亮度大于等于128的像素会被忽略
+    pub fn with_ignore_mask(mut self, mask: DynamicImage) -> HilltopParamAndResult {
+        self.ignore_mask = Some(mask);
+        self
+    }
+
+    /// 只在这块区域里找目标, 区域外全部忽略
+    pub fn with_roi(mut self, roi: BoundingBox) -> HilltopParamAndResult {
+        self.roi = Some(roi);
+        self
+    }
+
+    /// 自动判断目标个数, 见`StopRule`
+    pub fn with_stop_rule(mut self, stop_rule: StopRule) -> HilltopParamAndResult {
+        self.peak_options.stop_rule = stop_rule;
+        self
+    }
+
+    /// 返回的点之间至少隔开`min_distance`, 离已有结果太近的山峰直接丢掉, 点不够的时候结果会少于`top_n`
+    pub fn with_min_distance(mut self, min_distance: usize, shape: SuppressionShape) -> HilltopParamAndResult {
+        self.peak_options.suppression = Some(Suppression { min_distance, shape });
+        self
+    }
+
+    /// 对每个结果做亚像素细化, 结果写在`Point`的`fx`/`fy`里
+    pub fn with_subpixel(mut self, subpixel: bool) -> HilltopParamAndResult {
+        self.peak_options.subpixel = subpixel;
+        self
+    }
+
+    /// 把忽略的区域在差值图里清零, 超出挑战图的部分直接裁掉
+    fn apply_masks(&self, diff: &mut Grid<u64>) -> Result<()> {
+        let (width, height) = (diff.width(), diff.height());
+        let mut clear = |(left, top, right, bottom): BoundingBox| {
+            for y in top..=min(bottom, height - 1) {
+                for x in left..=min(right, width - 1) {
+                    diff[(x, y)] = 0;
+                }
+            }
+        };
+        if let Some((left, top, right, bottom)) = self.roi {
+            if left > right || top > bottom || left >= width || top >= height {
+                return Err(Error::InvalidParameter(format!("roi {:?} is outside the challenge image", (left, top, right, bottom))));
+            }
+            if top > 0 {
+                clear((0, 0, width - 1, top - 1));
+            }
+            if bottom + 1 < height {
+                clear((0, bottom + 1, width - 1, height - 1));
+            }
+            if left > 0 {
+                clear((0, top, left - 1, bottom));
+            }
+            if right + 1 < width {
+                clear((right + 1, top, width - 1, bottom));
+            }
         }
+        for &rect in &self.ignore_rects {
+            clear(rect);
+        }
+        if let Some(mask) = &self.ignore_mask {
+            if mask.dimensions() != (width as u32, height as u32) {
+                return Err(Error::SizeMismatch { expected: (width as u32, height as u32), actual: mask.dimensions() });
+            }
+            let mask = mask.to_luma8();
+            for (x, y, pixel) in mask.enumerate_pixels() {
+                if pixel[0] >= 128 {
+                    diff[(x as usize, y as usize)] = 0;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -75,7 +476,7 @@ impl XY {
 }
 
 struct AggregateMountain {
-    diff_data: Vec<Vec<u64>>,
+    diff_data: Grid<u64>,
     width: usize,
     height: usize,
     is_last: bool,
@@ -83,7 +484,7 @@ struct AggregateMountain {
 }
 
 impl AggregateMountain {
-    fn new(diff_data: Vec<Vec<u64>>, width: usize, height: usize) -> AggregateMountain {
+    fn new(diff_data: Grid<u64>, width: usize, height: usize) -> AggregateMountain {
         AggregateMountain {
             diff_data,
             width,
@@ -98,7 +499,7 @@ impl AggregateMountain {
             let mut xy = XY::new();
             for i in 0..self.width {
                 for j in 0..self.height {
-                    xy.update(i, j, self.diff_data[i][j]);
+                    xy.update(i, j, self.diff_data[(i, j)]);
                 }
             }
             return xy;
@@ -112,34 +513,21 @@ impl AggregateMountain {
         let mut xy = XY::new();
         for i in start_x..=end_x {
             for j in start_y..=end_y {
-                xy.update(i, j, self.diff_data[i][j]);
+                xy.update(i, j, self.diff_data[(i, j)]);
             }
         }
-        return xy;
+        xy
     }
 
     pub fn invalid_rectangle(&mut self, left_top_x: usize, left_top_y: usize, right_bottom_x: usize, right_bottom_y: usize) {
         if self.is_last {
             return;
         }
-        let mut next_start_x = left_top_x / 5;
-        let mut next_start_y = left_top_y / 5;
+        let next_start_x = left_top_x / 5;
+        let next_start_y = left_top_y / 5;
 
-        let mut next_end_x = (right_bottom_x + 4) / 5;
-        let mut next_end_y = (right_bottom_y + 4) / 5;
-
-
-        if left_top_x % 5 != 0 {
-            if next_start_x < 1 {
-                next_start_x = 0;
-            }
-        }
-
-        if left_top_y % 5 != 0 {
-            if next_start_y < 1 {
-                next_start_y = 0;
-            }
-        }
+        let mut next_end_x = right_bottom_x.div_ceil(5);
+        let mut next_end_y = right_bottom_y.div_ceil(5);
 
         if right_bottom_x % 5 != 0 {
             next_end_x = min(next_end_x + 1, self.next.as_ref().unwrap().width - 1);
@@ -149,27 +537,25 @@ impl AggregateMountain {
             next_end_y = min(next_end_y + 1, self.next.as_ref().unwrap().height - 1);
         }
 
-        // fill in next diff data
-        for x in next_start_x..=next_end_x {
-            for y in next_start_y..=next_end_y {
+        // fill in next diff data, 按行扫描对连续内存更友好, 每一行互不依赖
+        let next = self.next.as_mut().unwrap();
+        let (diff_data, width, height) = (&self.diff_data, self.width, self.height);
+        next.diff_data.for_each_row_mut(next_start_y, next_end_y, |y, row| {
+            let scan_start_y = y * 5;
+            let scan_end_y = min(scan_start_y + 4, height - 1);
+            for (x, cell) in row.iter_mut().enumerate().take(next_end_x + 1).skip(next_start_x) {
                 let scan_start_x = x * 5;
-                let scan_start_y = y * 5;
-
-                let scan_end_x = min(scan_start_x + 4, self.width - 1);
-                let scan_end_y = min(scan_start_y + 4, self.height - 1);
-                // let center_x = (scan_start_x + scan_end_x) / 2;
-                // let center_y = (scan_start_y + scan_end_y) / 2;
+                let scan_end_x = min(scan_start_x + 4, width - 1);
 
                 let mut aggregate_diff = 0;
-                for next_x in scan_start_x..=scan_end_x {
-                    for next_y in scan_start_y..=scan_end_y {
-                        aggregate_diff += self.diff_data[next_x][next_y];
-                    }
+                for next_y in scan_start_y..=scan_end_y {
+                    aggregate_diff += diff_data.row(next_y)[scan_start_x..=scan_end_x].iter().sum::<u64>();
                 }
-                self.next.as_mut().unwrap().diff_data[x][y] = aggregate_diff;
+                *cell = aggregate_diff;
             }
-            self.next.as_mut().unwrap().invalid_rectangle(next_start_x, next_start_y, next_end_x, next_end_y);
-        }
+        });
+        // 整块区域填完之后再往上一层传递一次就够了
+        next.invalid_rectangle(next_start_x, next_start_y, next_end_x, next_end_y);
     }
 
     pub fn gen_aggregate_mountain_mapping(&mut self) {
@@ -177,9 +563,9 @@ impl AggregateMountain {
             self.is_last = true;
             return;
         }
-        let next_width = (self.width + 4) / 5;
-        let next_height = (self.height + 4) / 5;
-        let next_data = vec![vec![0; next_height]; next_width];
+        let next_width = self.width.div_ceil(5);
+        let next_height = self.height.div_ceil(5);
+        let next_data = Grid::new(next_width, next_height, 0);
 
         self.next = Option::from(Box::new(AggregateMountain::new(next_data, next_width, next_height)));
 
@@ -197,16 +583,8 @@ struct Rectangle {
 impl Rectangle {
     pub fn rectangle_range(x: usize, y: usize, slice_size: usize, total_width: usize, total_height: usize) -> Rectangle {
         let half_slice_size = slice_size / 2;
-        let top_x = if x > half_slice_size {
-            x - half_slice_size
-        } else {
-            0
-        };
-        let top_y = if y > half_slice_size {
-            y - half_slice_size
-        } else {
-            0
-        };
+        let top_x = x.saturating_sub(half_slice_size);
+        let top_y = y.saturating_sub(half_slice_size);
         let mut right_bottom_x = x + half_slice_size;
         let mut right_bottom_y = y + half_slice_size;
 
@@ -228,63 +606,46 @@ impl Rectangle {
 
 pub fn sqrt(x: usize) -> usize {
     let mut a: usize = 1;
-    while a * a <= x as usize {
-        a = a + 1;
+    while a * a <= x {
+        a += 1;
     }
-    return (a - 1) as usize;
+    a - 1
 }
 
-fn _save_image(diff_data: &Vec<Vec<u64>>, width: usize, height: usize) {
-    let mut img: GrayImage = ImageBuffer::new(width as u32, height as u32);
-    let mut max_diff = 0;
-    for i in 0..width {
-        for j in 0..height {
-            if max_diff < diff_data[i][j] {
-                max_diff = diff_data[i][j];
-            }
-        }
-    }
-    for i in 0..width {
-        for j in 0..height {
-            let rgb = diff_data[i][j] * 255 / max_diff;
-            let rgb_gray = (rgb << 24 | rgb << 16 | rgb << 8 | rgb) as u8;
-            img.put_pixel(i as u32, j as u32, image::Pixel::from_channels(rgb_gray, rgb_gray, rgb_gray, 100));
-        }
-    }
-    // img.save("./src/images/gray_image.png").unwrap();
+/// `adjust_center_point`里的缩略图山峰, 导出调试图用
+struct ShortCurtMountain {
+    grid: Grid<u64>,
+    /// 缩略图左上角在原图里的位置
+    left: usize,
+    top: usize,
+    /// 缩略图一格是原图里多少像素
+    thumb_times: u32,
 }
 
-
-fn adjust_center_point(top_xy: XY, mountain: &AggregateMountain, result: &HilltopParamAndResult, result_width: usize, result_height: usize, result_diff: &Vec<Vec<i32>>) -> XY {
-    let points = Rectangle::rectangle_range(top_xy.x, top_xy.y, (result.ch_size * 2) as usize, result_width, result_height);
-    let thumb_times = sqrt(result.ch_size as usize) as u32;
-    let mut short_curt_width = result.ch_size / thumb_times;
+fn adjust_center_point(top_xy: XY, mountain: &AggregateMountain, ch_size: u32, result_width: usize, result_height: usize, integral: &IntegralImage) -> (XY, ShortCurtMountain) {
+    let points = Rectangle::rectangle_range(top_xy.x, top_xy.y, (ch_size * 2) as usize, result_width, result_height);
+    let thumb_times = sqrt(ch_size as usize) as u32;
+    let mut short_curt_width = ch_size / thumb_times;
     short_curt_width *= 2;
-    let mut short_curt = vec![vec![0; short_curt_width as usize]; short_curt_width as usize];
+    let mut short_curt = Grid::new(short_curt_width as usize, short_curt_width as usize, 0u64);
 
+    // 缩略图每一格是原图里thumb_times x thumb_times的块, 用积分图O(1)求和, 超出原图的部分为0
     for i in 0..short_curt_width {
         for j in 0..short_curt_width {
-            let start_x = i * thumb_times + points.top_x as u32;
-            let start_y = j * thumb_times + points.top_y as u32;
-
-            let end_x = min(start_x + thumb_times - 1, (result_width - 1) as u32);
-            let end_y = min(start_y + thumb_times - 1, (result_height - 1) as u32);
-
-            let mut total_diff = 0u64;
-
-            for x in start_x..=end_x {
-                for y in start_y..=end_y {
-                    total_diff += result_diff[x as usize][y as usize] as u64;
-                }
-            }
-            short_curt[i as usize][j as usize] = total_diff;
+            let start_x = (i * thumb_times) as usize + points.top_x;
+            let start_y = (j * thumb_times) as usize + points.top_y;
+            let end_x = start_x + thumb_times as usize - 1;
+            let end_y = start_y + thumb_times as usize - 1;
+            short_curt[(i as usize, j as usize)] = integral.sum(start_x, start_y, end_x, end_y);
         }
     }
 
     let short_curt_mountain_width = short_curt_width / 2;
 
+    // ch_size很小的时候半径可能是0, 这时只有中心一个点, 权重为1
+    let short_curt_radius = SQRT_2 * (short_curt_mountain_width / 2) as f64;
     let mut short_curt_xy = XY::new();
-    let mut short_curt_mountain = vec![vec![0; short_curt_mountain_width as usize]; short_curt_mountain_width as usize];
+    let mut short_curt_mountain = Grid::new(short_curt_mountain_width as usize, short_curt_mountain_width as usize, 0u64);
     for i in 0..short_curt_mountain_width {
         for j in 0..short_curt_mountain_width {
             let center_x = i + short_curt_mountain_width / 2;
@@ -293,9 +654,9 @@ fn adjust_center_point(top_xy: XY, mountain: &AggregateMountain, result: &Hillto
             let mut aggregate_diff = 0.0;
             for x in rect.top_x..=rect.bottom_x {
                 for y in rect.top_y..=rect.bottom_y {
-                    let base = short_curt[x][y] as f64;
-                    let distance = (((x as f64 - center_x as f64) * (x as f64 - center_x as f64) + (y as f64 - center_y as f64) * (y as f64 - center_y as f64)) as f64).sqrt();
-                    let distance_ratio = distance / (SQRT_2 * ((short_curt_mountain_width) / 2) as f64);
+                    let base = short_curt[(x, y)] as f64;
+                    let distance = ((x as f64 - center_x as f64) * (x as f64 - center_x as f64) + (y as f64 - center_y as f64) * (y as f64 - center_y as f64)).sqrt();
+                    let distance_ratio = if short_curt_radius > 0.0 { distance / short_curt_radius } else { 0.0 };
                     if distance_ratio > 1.0 {
                         continue;
                     }
@@ -303,131 +664,297 @@ fn adjust_center_point(top_xy: XY, mountain: &AggregateMountain, result: &Hillto
                     aggregate_diff += base * base * base * ratio;
                 }
             }
-            short_curt_mountain[i as usize][j as usize] = aggregate_diff as usize;
+            short_curt_mountain[(i as usize, j as usize)] = aggregate_diff as u64;
             short_curt_xy.update(center_x as usize, center_y as usize, aggregate_diff as u64);
         }
     }
 
-    // 在缩略图里面寻找最高点，之后再回放到原图进行
-    let real_start_x = short_curt_xy.x as usize * thumb_times as usize + points.top_x;
-    let real_end_x = short_curt_xy.x * thumb_times as usize + thumb_times as usize + points.top_x;
-    let real_start_y = short_curt_xy.y * thumb_times as usize + points.top_y;
-    let real_end_y = short_curt_xy.y * thumb_times as usize + thumb_times as usize + points.top_y;
+    // 在缩略图里面寻找最高点，之后再回放到原图进行, 贴着右边和下边的时候要裁剪到原图范围内
+    let real_start_x = min(short_curt_xy.x * thumb_times as usize + points.top_x, result_width - 1);
+    let real_end_x = min(short_curt_xy.x * thumb_times as usize + thumb_times as usize + points.top_x, result_width - 1);
+    let real_start_y = min(short_curt_xy.y * thumb_times as usize + points.top_y, result_height - 1);
+    let real_end_y = min(short_curt_xy.y * thumb_times as usize + thumb_times as usize + points.top_y, result_height - 1);
 
-    let mut xy = XY::new();
+    // 窗口里全是0的话保持金字塔找到的点
+    let mut xy = XY { x: top_xy.x, y: top_xy.y, weight: 0 };
     for i in real_start_x..=real_end_x {
         for j in real_start_y..=real_end_y {
-            let rect = Rectangle::rectangle_range(i, j, result.ch_size as usize, result_width, result_height);
-            let mut aggregate_diff = 0.0;
-            for x in rect.top_x..=rect.bottom_x {
-                for y in rect.top_y..=rect.bottom_y {
-                    let distance = (((x as i64 - i as i64).pow(2) + (y as i64 - j as i64).pow(2)) as f64).sqrt();
-                    let distance_ratio = distance / (SQRT_2 * (result.ch_size / 2) as f64);
-                    if distance_ratio > 1.0 {
-                        continue;
-                    }
-                    let ratio = ((PI * distance_ratio).cos() + 1.0) / 2.0;
-                    aggregate_diff += mountain.diff_data[x][y] as f64 * ratio;
-                }
-            }
+            let aggregate_diff = window_weight(mountain, i, j, ch_size, result_width, result_height);
             xy.update(i, j, aggregate_diff as u64);
         }
     }
 
-    xy
+    (xy, ShortCurtMountain { grid: short_curt_mountain, left: points.top_x, top: points.top_y, thumb_times })
 }
 
-fn _vec_hash(data: &Vec<Vec<u64>>, width: usize, height: usize) -> f64 {
-    let mut hash = 0.0;
-    for i in 0..width {
-        for j in 0..height {
-            hash += data[i][j] as f64;
+/// 以`(i, j)`为中心、边长`ch_size`的窗口里差值的加权和, 离中心越远权重越低(余弦衰减)
+fn window_weight(mountain: &AggregateMountain, i: usize, j: usize, ch_size: u32, result_width: usize, result_height: usize) -> f64 {
+    let radius = SQRT_2 * (ch_size / 2) as f64;
+    let rect = Rectangle::rectangle_range(i, j, ch_size as usize, result_width, result_height);
+    let mut aggregate_diff = 0.0;
+    for x in rect.top_x..=rect.bottom_x {
+        for y in rect.top_y..=rect.bottom_y {
+            let distance = (((x as i64 - i as i64).pow(2) + (y as i64 - j as i64).pow(2)) as f64).sqrt();
+            // ch_size很小的时候半径可能是0, 这时只有中心一个点, 权重为1
+            let distance_ratio = if radius > 0.0 { distance / radius } else { 0.0 };
+            if distance_ratio > 1.0 {
+                continue;
+            }
+            let ratio = ((PI * distance_ratio).cos() + 1.0) / 2.0;
+            aggregate_diff += mountain.diff_data[(x, y)] as f64 * ratio;
         }
     }
-    hash
+    aggregate_diff
+}
+
+/// 亚像素细化: 用整数峰值周围3x3的窗口权重拟合二次曲面`f(x, y)`, 取曲面的极大值点。
+/// 曲面不是开口向下的话退化成两个方向分别拟合抛物线, 偏移量限制在一个像素以内
+fn refine_subpixel(mountain: &AggregateMountain, top_xy: &XY, ch_size: u32, result_width: usize, result_height: usize) -> (f64, f64) {
+    let (x, y) = (top_xy.x, top_xy.y);
+    // 贴着边的方向没有两侧的邻居, 不细化
+    let refine_x = x > 0 && x + 1 < result_width;
+    let refine_y = y > 0 && y + 1 < result_height;
+    let score = |dx: i64, dy: i64| {
+        window_weight(mountain, (x as i64 + dx) as usize, (y as i64 + dy) as usize, ch_size, result_width, result_height)
+    };
+    let center = score(0, 0);
+    let (mut gx, mut hxx) = (0.0, 0.0);
+    if refine_x {
+        let (left, right) = (score(-1, 0), score(1, 0));
+        gx = (right - left) / 2.0;
+        hxx = right - 2.0 * center + left;
+    }
+    let (mut gy, mut hyy) = (0.0, 0.0);
+    if refine_y {
+        let (top, bottom) = (score(0, -1), score(0, 1));
+        gy = (bottom - top) / 2.0;
+        hyy = bottom - 2.0 * center + top;
+    }
+    let hxy = if refine_x && refine_y {
+        (score(1, 1) - score(1, -1) - score(-1, 1) + score(-1, -1)) / 4.0
+    } else {
+        0.0
+    };
+
+    // 二次曲面的极值点: H * offset = -g, 开口向下要求H负定
+    let det = hxx * hyy - hxy * hxy;
+    let (offset_x, offset_y) = if hxx < 0.0 && hyy < 0.0 && det > 0.0 {
+        ((-hyy * gx + hxy * gy) / det, (hxy * gx - hxx * gy) / det)
+    } else {
+        let parabola = |g: f64, h: f64| if h < 0.0 { -g / h } else { 0.0 };
+        (parabola(gx, hxx), parabola(gy, hyy))
+    };
+    (x as f64 + offset_x.clamp(-1.0, 1.0), y as f64 + offset_y.clamp(-1.0, 1.0))
 }
 
-pub fn find_top_n(mut result: HilltopParamAndResult) -> Vec<Point> {
+/// `adjust_center_point`里一个窗口能得到的最大权重, 用来把权重归一化成置信度
+fn window_capacity(ch_size: u32) -> f64 {
+    let half = (ch_size / 2) as i64;
+    let radius = SQRT_2 * half as f64;
+    let mut capacity = 0.0;
+    for x in -half..=half {
+        for y in -half..=half {
+            let distance = ((x * x + y * y) as f64).sqrt();
+            let distance_ratio = if radius > 0.0 { distance / radius } else { 0.0 };
+            if distance_ratio > 1.0 {
+                continue;
+            }
+            capacity += ((PI * distance_ratio).cos() + 1.0) / 2.0;
+        }
+    }
+    capacity * MAX_RGB_DIFF as f64
+}
+
+pub fn find_top_n(result: HilltopParamAndResult) -> Result<Vec<Point>> {
+    find_top_n_inner(result, None)
+}
+
+/// 和`find_top_n`一样, 另外返回差值热力图、金字塔每一层、每个山峰的削峰区域和缩略图山峰,
+/// 识别失败的时候用来排查, 见`DebugArtifacts`
+pub fn find_top_n_with_debug(result: HilltopParamAndResult) -> Result<(Vec<Point>, DebugArtifacts)> {
+    let mut debug = DebugArtifacts::new();
+    let points = find_top_n_inner(result, Some(&mut debug))?;
+    Ok((points, debug))
+}
+
+fn find_top_n_inner(result: HilltopParamAndResult, debug: Option<&mut DebugArtifacts>) -> Result<Vec<Point>> {
     // 挑战图的宽和高
     let width = result.challenge_image.width() as usize;
     let height = result.challenge_image.height() as usize;
 
-    // 缩放底图，如果宽和高不一致的话
-    let bg_image = result.background_image.clone();
-    let cg_image = result.challenge_image.clone();
-
-    // 这里写法好像有点bug, 目前没解决, 就当图都一样大吧，不一样自己用open-cv处理一下, ^.^
-    // if (bg_image.width() != result.width) || (bg_image.height() != result.height) {
-    //     bg_image = bg_image.thumbnail(result.width, result.height);
-    // }
+    if result.ch_size == 0 {
+        return Err(Error::InvalidParameter("ch_size must be greater than 0".to_string()));
+    }
+    result.peak_options.stop_rule.validate()?;
+    if width == 0 || height == 0 {
+        return Err(Error::EmptyInput("challenge image has no pixels".to_string()));
+    }
 
-    let mut total_diff = 0u64;
-    let mut diff = vec![vec![0; height]; width];
-    let mut calculate_diff = vec![vec![0u64; height]; width];
+    // 底图和挑战图宽高不一致的话, 按配置的策略缩放或者裁剪底图
+    let bg_image = result.size_strategy.align(&result.background_image, width as u32, height as u32)?;
+    let cg_image = result.challenge_image.clone();
 
     // 计算背景图和挑战图的像素差
-    for i in 0..width {
-        for j in 0..height {
-            let rgb_diff = rgb_diff(cg_image.get_pixel(i as u32, j as u32), bg_image.get_pixel(i as u32, j as u32));
-            diff[i][j] = rgb_diff;
-            calculate_diff[i][j] = rgb_diff as u64;
-            total_diff += rgb_diff as u64;
-        }
-    }
+    let metric = result.diff_metric;
+    let mut diff = Grid::from_fn(width, height, |i, j| {
+        metric.diff(cg_image.get_pixel(i as u32, j as u32), bg_image.get_pixel(i as u32, j as u32)) as u64
+    });
+    // 忽略的区域在建金字塔之前清零
+    result.apply_masks(&mut diff)?;
+
+    Ok(find_peaks(diff, result.ch_size, result.top_n, &result.peak_options, debug))
+}
 
-    let avg_diff = total_diff as f64 / (width * height) as f64;
+/// 在任意一张"差异图"上找`top_n`个山峰, 每个山峰是边长大约`ch_size`的一块高值区域。
+/// 值的量级需要和`rgb_diff`一致(`0..=MAX_RGB_DIFF`), 否则置信度没有意义。
+/// 设置了`stop_rule`的时候`top_n`只是上限, `suppression`见`HilltopParamAndResult::with_min_distance`。
+/// `debug`不为空的时候把中间结果记下来
+pub(crate) fn find_peaks(diff: Grid<u64>, ch_size: u32, top_n: usize, options: &PeakOptions,
+                         mut debug: Option<&mut DebugArtifacts>) -> Vec<Point> {
+    let PeakOptions { stop_rule, suppression, subpixel } = *options;
+    let (width, height) = (diff.width(), diff.height());
+    // 原始差值的积分图, 削峰只改金字塔, 这里保持不变
+    let integral = IntegralImage::new(&diff);
 
-    let mut mountain = AggregateMountain::new(calculate_diff, width, height);
+    let mut mountain = AggregateMountain::new(diff, width, height);
     mountain.gen_aggregate_mountain_mapping();
     mountain.invalid_rectangle(0, 0, width - 1, height - 1);
+    if let Some(debug) = debug.as_deref_mut() {
+        record_pyramid(debug, &mountain, ch_size, top_n);
+    }
+
+    let mut ret: Vec<Point> = vec![];
+    let capacity = window_capacity(ch_size);
+    // 整张图都是平均差值的时候一个窗口的权重
+    let avg_diff = integral.sum(0, 0, width - 1, height - 1) as f64 / (width * height) as f64;
+    let avg_weight = capacity / MAX_RGB_DIFF as f64 * avg_diff;
 
-    let mut ret = vec![];
-    result.avg_diff = avg_diff as u32;
+    while ret.len() < top_n {
+        let peak = mountain.fetch_top_point();
+        let (peak_x, peak_y, peak_weight) = (peak.x, peak.y, peak.weight);
+        let (top_xy, short_curt) = adjust_center_point(peak, &mountain, ch_size, width, height, &integral);
 
-    for i in 0..result.top_n {
-        let mut top_xy = mountain.fetch_top_point();
-        top_xy = adjust_center_point(top_xy, &mountain, &result, width, height, &diff);
-        let point = Point::new(top_xy.x, top_xy.y, top_xy.weight as usize);
+        if let Some(Suppression { min_distance, shape }) = suppression {
+            let too_close = ret.iter().any(|p| shape.within(top_xy.x as i64 - p.x as i64, top_xy.y as i64 - p.y as i64, min_distance));
+            if too_close {
+                // 金字塔已经空了, 剩下的山峰都是0
+                if peak_weight == 0 {
+                    break;
+                }
+                // 金字塔的最高点一定大于0, 清掉之后下一轮一定会换一个山峰
+                suppress_around(&mut mountain, peak_x, peak_y, max(ch_size as usize / 2, 1), shape);
+                continue;
+            }
+        }
+
+        let first = ret.first().map_or(top_xy.weight, |p| p.weight as u64);
+        if stop_rule.should_stop(top_xy.weight, first, avg_weight) {
+            break;
+        }
+        let rect = Rectangle::rectangle_range(top_xy.x, top_xy.y, ch_size as usize, width, height);
+        let confidence = (top_xy.weight as f64 / capacity).clamp(0.0, 1.0);
+        let mut point = Point::new(top_xy.x, top_xy.y, top_xy.weight as usize, confidence,
+                                   (rect.top_x, rect.top_y, rect.bottom_x, rect.bottom_y), ret.len() + 1);
+        if subpixel {
+            let (fx, fy) = refine_subpixel(&mountain, &top_xy, ch_size, width, height);
+            point = point.with_subpixel(fx, fy);
+        }
         ret.push(point);
 
-        if i < result.top_n - 1 {
-            trip_aggregate_mountain(&mut mountain, top_xy, &result, width, height);
+        if let Some(debug) = debug.as_deref_mut() {
+            let meta = vec![("rank", point.rank.to_json()), ("left", short_curt.left.to_json()),
+                            ("top", short_curt.top.to_json()), ("thumb_times", short_curt.thumb_times.to_json())];
+            debug.push_grid(format!("peak_{}_short_curt_mountain", point.rank), "short_curt_mountain", &short_curt.grid, false, meta);
         }
+        if ret.len() < top_n {
+            // 削峰前后的差值之差就是这个山峰被抑制掉的部分
+            let before = debug.is_some().then(|| mountain.diff_data.clone());
+            trip_aggregate_mountain(&mut mountain, top_xy, ch_size, width, height);
+            if let Some(Suppression { min_distance, shape }) = suppression {
+                suppress_around(&mut mountain, point.x, point.y, min_distance, shape);
+            }
+            if let (Some(debug), Some(before)) = (debug.as_deref_mut(), before) {
+                let removed = Grid::from_fn(width, height, |x, y| before[(x, y)] - mountain.diff_data[(x, y)]);
+                let meta = vec![("rank", point.rank.to_json()), ("x", point.x.to_json()), ("y", point.y.to_json())];
+                debug.push_grid(format!("peak_{}_suppression", point.rank), "suppression", &removed, false, meta);
+            }
+        }
+    }
+    if let Some(debug) = debug {
+        debug.set_summary("points", ret.iter().map(Point::to_json).collect::<Vec<Json>>());
     }
     ret
 }
 
-fn trip_aggregate_mountain(mountain: &mut AggregateMountain, top_xy: XY, result: &HilltopParamAndResult, result_width: usize, result_height: usize) {
-    let start_x = max(top_xy.x - result.ch_size as usize / 2, 0);
-    let end_x = min(top_xy.x + result.ch_size as usize / 2, (result_width - 1) as usize);
-    let start_y = max(top_xy.y - result.ch_size as usize / 2, 0);
-    let end_y = min(top_xy.y + result.ch_size as usize / 2, (result_height - 1) as usize);
+/// 差值热力图和金字塔每一层, 金字塔第0层就是差值图本身
+fn record_pyramid(debug: &mut DebugArtifacts, mountain: &AggregateMountain, ch_size: u32, top_n: usize) {
+    debug.set_summary("width", mountain.width);
+    debug.set_summary("height", mountain.height);
+    debug.set_summary("ch_size", ch_size);
+    debug.set_summary("top_n", top_n);
+    debug.push_grid("diff".to_string(), "diff", &mountain.diff_data, true, vec![]);
+    let mut level = mountain.next.as_deref();
+    let mut index = 1;
+    while let Some(current) = level {
+        // 每一格是原图里`scale` x `scale`的块
+        let meta = vec![("level", index.to_json()), ("scale", 5usize.pow(index).to_json())];
+        debug.push_grid(format!("pyramid_{}", index), "pyramid", &current.diff_data, false, meta);
+        level = current.next.as_deref();
+        index += 1;
+    }
+}
+
+/// 把`(cx, cy)`周围`distance`以内的差值清零, 金字塔跟着更新
+fn suppress_around(mountain: &mut AggregateMountain, cx: usize, cy: usize, distance: usize, shape: SuppressionShape) {
+    if distance == 0 {
+        return;
+    }
+    let radius = distance - 1;
+    let start_x = cx.saturating_sub(radius);
+    let end_x = min(cx + radius, mountain.width - 1);
+    let start_y = cy.saturating_sub(radius);
+    let end_y = min(cy + radius, mountain.height - 1);
+    for y in start_y..=end_y {
+        for x in start_x..=end_x {
+            if shape.within(x as i64 - cx as i64, y as i64 - cy as i64, distance) {
+                mountain.diff_data[(x, y)] = 0;
+            }
+        }
+    }
+    mountain.invalid_rectangle(start_x, start_y, end_x, end_y);
+}
+
+fn trip_aggregate_mountain(mountain: &mut AggregateMountain, top_xy: XY, ch_size: u32, result_width: usize, result_height: usize) {
+    // 窗口用有符号数算完再裁剪到图片范围内, 目标贴着左边或者上边的时候不会下溢
+    let half = (ch_size / 2) as i64;
+    let (center_x, center_y) = (top_xy.x as i64, top_xy.y as i64);
+    let start_x = (center_x - half).clamp(0, result_width as i64 - 1) as usize;
+    let end_x = (center_x + half).clamp(0, result_width as i64 - 1) as usize;
+    let start_y = (center_y - half).clamp(0, result_height as i64 - 1) as usize;
+    let end_y = (center_y + half).clamp(0, result_height as i64 - 1) as usize;
 
     let mut max_diff = 0;
     for x in start_x..=end_x {
         for y in start_y..=end_y {
-            if mountain.diff_data[x][y] > max_diff {
-                max_diff = mountain.diff_data[x][y];
+            if mountain.diff_data[(x, y)] > max_diff {
+                max_diff = mountain.diff_data[(x, y)];
             }
         }
     }
 
     for x in start_x..=end_x {
         for y in start_y..=end_y {
-            let distance = ((x as i64 - top_xy.x as i64).pow(2) as f64 + (y as i64 - top_xy.y as i64).pow(2) as f64).sqrt();
-            let distance_ratio = distance / result.ch_size as f64;
+            let distance = (((x as i64 - center_x).pow(2) + (y as i64 - center_y).pow(2)) as f64).sqrt();
+            let distance_ratio = distance / ch_size as f64;
             if distance_ratio > 1.0 {
                 continue;
             }
             // y = 1- x*x / 2.25 权值衰减函数，为2次函数，要求命中坐标: (0,1) (1.5,0)
             // 当距离为0的时候，衰减权重为1，当距离为1.5的时候，衰减权重为0
             // 当距离为1的时候， 衰减权重为：1- 1/2.25 = 0.55
-            mountain.diff_data[x][y] = (mountain.diff_data[x][y] as f64 - (max_diff as f64 * (1.0 - distance_ratio * distance_ratio / 2.25))) as u64;
-
-            // 这块逻辑我也没测试到走这块，有可能有特定的图可能会overflow吧，目前没测试到, 如果存usize的话, 这块是不会走的
-            // if mountain.diff_data[x][y] < 0 {
-            //     mountain.diff_data[x][y] = 0;
-            // }
+            let decay = max_diff as f64 * (1.0 - distance_ratio * distance_ratio / 2.25);
+            // 削峰之后最低到0, 不能在u64上直接减
+            mountain.diff_data[(x, y)] = (mountain.diff_data[(x, y)] as f64 - decay).max(0.0) as u64;
         }
     }
     mountain.invalid_rectangle(start_x, start_y, end_x, end_y);
@@ -435,15 +962,390 @@ fn trip_aggregate_mountain(mountain: &mut AggregateMountain, top_xy: XY, result:
 
 #[cfg(test)]
 mod tests {
-    use crate::image_hill_top_v2::{HilltopParamAndResult, find_top_n};
+    use crate::error::Error;
+    use crate::image_avg_merger::{avg, MergeOptions};
+    use crate::color_diff::DiffMetric;
+    use crate::test_util;
+    use crate::image_hill_top_v2::{HilltopParamAndResult, SizeStrategy, StopRule, SuppressionShape, find_top_n, find_top_n_with_debug};
+    use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+    use image::imageops::FilterType;
+    use proptest::prelude::*;
+
+    fn load_images() -> (image::DynamicImage, image::DynamicImage) {
+        let mut input = test_util::load_images();
+        let bg_image = avg(&input, &MergeOptions::default()).unwrap();
+        (bg_image, input.remove(0))
+    }
 
     #[test]
     fn test() {
-        let bg_image = image::open("./src/images/bg_image.png").unwrap();
-        let cg_image = image::open("./src/images/cg_image.png").unwrap();
-        let result = HilltopParamAndResult::new(bg_image, cg_image, 75, 2);
-        let result = find_top_n(result);
+        let (bg_image, cg_image) = load_images();
+        let result = HilltopParamAndResult::new(bg_image, cg_image, 40, 2);
+        let result = find_top_n(result).unwrap();
         println!("{}", result.len());
         println!("{:?}", result);
+        assert_eq!(result.len(), 2);
+    }
+
+    #[test]
+    fn test_exact_result() {
+        // 单线程和`parallel`特性下结果必须完全一致
+        let (bg_image, cg_image) = load_images();
+        let result = find_top_n(HilltopParamAndResult::new(bg_image, cg_image, 40, 3)).unwrap();
+        let points: Vec<(usize, usize, usize)> = result.iter().map(|p| (p.x, p.y, p.weight)).collect();
+        assert_eq!(points, vec![(111, 40, 84736), (218, 42, 59737), (41, 69, 31679)]);
+    }
+
+    #[test]
+    fn test_diff_metric() {
+        let (bg_image, cg_image) = load_images();
+        let expected = [(111, 40), (218, 42)];
+        for metric in ["l2", "cie76", "ciede2000", "luma", "hue"] {
+            let param = HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 40, 2)
+                .with_diff_metric(metric.parse().unwrap());
+            let result = find_top_n(param).unwrap();
+            assert!(result.iter().all(|p| p.confidence <= 1.0));
+            // 不同算法的权重不一样, 至少要找到最明显的那个目标
+            assert!(result.iter().any(|p| expected.iter().any(|e| p.x.abs_diff(e.0) <= 5 && p.y.abs_diff(e.1) <= 5)),
+                    "{}: {:?}", metric, result);
+        }
+        let param = HilltopParamAndResult::new(bg_image, cg_image, 40, 2).with_diff_metric(DiffMetric::L1);
+        let points: Vec<(usize, usize)> = find_top_n(param).unwrap().iter().map(|p| (p.x, p.y)).collect();
+        assert_eq!(points, expected);
+    }
+
+    fn top_points(bg_image: DynamicImage, cg_image: DynamicImage, size_strategy: SizeStrategy) -> Vec<(usize, usize)> {
+        let param = HilltopParamAndResult::new(bg_image, cg_image, 40, 2).with_size_strategy(size_strategy);
+        let mut points: Vec<(usize, usize)> = find_top_n(param).unwrap().iter().map(|p| (p.x, p.y)).collect();
+        // 缩放之后两个目标的权重先后可能变化, 这里只比较位置
+        points.sort();
+        points
+    }
+
+    fn assert_near(actual: &[(usize, usize)], expected: &[(usize, usize)], tolerance: usize) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(a.0.abs_diff(e.0) <= tolerance && a.1.abs_diff(e.1) <= tolerance, "{:?} vs {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_size_strategy() {
+        let (bg_image, cg_image) = load_images();
+        let expected = top_points(bg_image.clone(), cg_image.clone(), SizeStrategy::Reject);
+
+        // 底图分辨率更高
+        for (w, h) in [(600, 300), (450, 225)] {
+            let scaled = bg_image.resize_exact(w, h, FilterType::Triangle);
+            assert_near(&top_points(scaled, cg_image.clone(), SizeStrategy::Resize), &expected, 3);
+        }
+
+        // 底图更小, 放大之后细节有损失, 至少要能找到一个目标
+        let scaled = bg_image.resize_exact(270, 135, FilterType::Triangle);
+        let actual = top_points(scaled, cg_image.clone(), SizeStrategy::Resize);
+        assert_eq!(actual.len(), 2);
+        assert!(actual.iter().any(|a| expected.iter().any(|e| a.0.abs_diff(e.0) <= 3 && a.1.abs_diff(e.1) <= 3)));
+
+        // 底图更大, 挑战图内容在左上角
+        let mut larger = DynamicImage::new_rgba8(bg_image.width() + 30, bg_image.height() + 20);
+        larger.copy_from(&bg_image, 0, 0).unwrap();
+        assert_near(&top_points(larger, cg_image.clone(), SizeStrategy::CropTopLeft), &expected, 0);
+
+        // 底图更大, 挑战图内容在中间
+        let mut larger = DynamicImage::new_rgba8(bg_image.width() + 30, bg_image.height() + 20);
+        larger.copy_from(&bg_image, 15, 10).unwrap();
+        assert_near(&top_points(larger, cg_image.clone(), SizeStrategy::CenterCrop), &expected, 0);
+    }
+
+    #[test]
+    fn test_point_detail() {
+        let (bg_image, cg_image) = load_images();
+        let (width, height) = cg_image.dimensions();
+        let result = find_top_n(HilltopParamAndResult::new(bg_image, cg_image, 40, 3)).unwrap();
+        for (i, point) in result.iter().enumerate() {
+            assert_eq!(point.rank, i + 1);
+            assert!(point.confidence > 0.0 && point.confidence <= 1.0);
+            let (left, top, right, bottom) = point.bbox;
+            assert!(left <= point.x && point.x <= right && right < width as usize);
+            assert!(top <= point.y && point.y <= bottom && bottom < height as usize);
+        }
+        assert!(result[0].confidence >= result[1].confidence);
+        assert_eq!(result[0].bbox, (result[0].x - 20, result[0].y - 20, result[0].x + 20, result[0].y + 20));
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let (bg_image, cg_image) = load_images();
+        let small_bg = bg_image.crop_imm(0, 0, 100, 100);
+        let result = HilltopParamAndResult::new(small_bg.clone(), cg_image.clone(), 40, 2).with_size_strategy(SizeStrategy::Reject);
+        assert!(matches!(find_top_n(result), Err(Error::SizeMismatch { .. })));
+        let result = HilltopParamAndResult::new(small_bg, cg_image.clone(), 40, 2).with_size_strategy(SizeStrategy::CenterCrop);
+        assert!(matches!(find_top_n(result), Err(Error::SizeMismatch { .. })));
+        assert!("stretch".parse::<SizeStrategy>().is_err());
+
+        let result = HilltopParamAndResult::new(bg_image, cg_image, 0, 2);
+        assert!(matches!(find_top_n(result), Err(Error::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn test_ignore_regions() {
+        let (bg_image, cg_image) = load_images();
+        let near = |p: &crate::image_hill_top_v2::Point, x: usize, y: usize| p.x.abs_diff(x) <= 5 && p.y.abs_diff(y) <= 5;
+
+        // 忽略最明显的目标之后, 原来第二的目标排到第一
+        let param = HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 40, 2)
+            .with_ignore_rect((90, 20, 130, 60));
+        let result = find_top_n(param).unwrap();
+        assert!(near(&result[0], 218, 42), "{:?}", result);
+        assert!(result.iter().all(|p| !near(p, 111, 40)), "{:?}", result);
+
+        // 掩码效果一样
+        let mut mask = DynamicImage::new_luma8(cg_image.width(), cg_image.height());
+        for y in 20..=60 {
+            for x in 90..=130 {
+                mask.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+            }
+        }
+        let param = HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 40, 2).with_ignore_mask(mask);
+        let masked = find_top_n(param).unwrap();
+        assert_eq!(masked, result);
+
+        // 只在左半边找
+        let param = HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 40, 2).with_roi((0, 0, 149, 149));
+        let result = find_top_n(param).unwrap();
+        assert!(near(&result[0], 111, 40), "{:?}", result);
+        assert!(result.iter().all(|p| p.x < 150), "{:?}", result);
+
+        let param = HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 40, 2).with_roi((400, 0, 500, 10));
+        assert!(matches!(find_top_n(param), Err(Error::InvalidParameter(_))));
+        let param = HilltopParamAndResult::new(bg_image, cg_image, 40, 2).with_ignore_mask(DynamicImage::new_luma8(10, 10));
+        assert!(matches!(find_top_n(param), Err(Error::SizeMismatch { .. })));
+    }
+
+    #[test]
+    fn test_stop_rule() {
+        // 测试图里有3个目标, 第4个山峰的权重只有第3个的七分之一
+        let (bg_image, cg_image) = load_images();
+        let rules = [
+            StopRule { min_peak_ratio: Some(0.2), min_avg_ratio: None },
+            StopRule { min_peak_ratio: None, min_avg_ratio: Some(2.0) },
+            StopRule { min_peak_ratio: Some(0.2), min_avg_ratio: Some(2.0) },
+        ];
+        for rule in rules {
+            let param = HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 40, 8).with_stop_rule(rule);
+            let points: Vec<(usize, usize)> = find_top_n(param).unwrap().iter().map(|p| (p.x, p.y)).collect();
+            assert_eq!(points, vec![(111, 40), (218, 42), (41, 69)], "{:?}", rule);
+        }
+
+        // top_n仍然是上限
+        let rule = StopRule { min_peak_ratio: Some(0.2), min_avg_ratio: None };
+        let param = HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 40, 2).with_stop_rule(rule);
+        assert_eq!(find_top_n(param).unwrap().len(), 2);
+
+        // 一模一样的两张图没有目标
+        let param = HilltopParamAndResult::new(cg_image.clone(), cg_image.clone(), 40, 8).with_stop_rule(rule);
+        assert!(find_top_n(param).unwrap().is_empty());
+
+        let rule = StopRule { min_peak_ratio: Some(-1.0), min_avg_ratio: None };
+        let param = HilltopParamAndResult::new(bg_image, cg_image, 40, 8).with_stop_rule(rule);
+        assert!(matches!(find_top_n(param), Err(Error::InvalidParameter(_))));
+    }
+
+    /// 纯灰色底图上画几个白色矩形`(left, top, right, bottom)`
+    fn draw_targets(rects: &[(u32, u32, u32, u32)]) -> (DynamicImage, DynamicImage) {
+        let mut bg_image = DynamicImage::new_rgba8(200, 100);
+        for y in 0..100 {
+            for x in 0..200 {
+                bg_image.put_pixel(x, y, Rgba([120, 120, 120, 255]));
+            }
+        }
+        let mut cg_image = bg_image.clone();
+        for &(left, top, right, bottom) in rects {
+            for y in top..=bottom {
+                for x in left..=right {
+                    cg_image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+                }
+            }
+        }
+        (bg_image, cg_image)
+    }
+
+    #[test]
+    fn test_min_distance() {
+        // 一个长条和两个挨在一起的方块, 两个方块的中心相距20
+        let (bg_image, cg_image) = draw_targets(&[(20, 40, 79, 53), (110, 40, 125, 55), (130, 40, 145, 55)]);
+
+        // 不做抑制的话同一个长条上会找到好几个点
+        let result = find_top_n(HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 20, 4)).unwrap();
+        assert!(result.iter().filter(|p| p.x < 80).count() > 2, "{:?}", result);
+
+        for (shape, name) in [(SuppressionShape::Circle, "circle"), (SuppressionShape::Rect, "rect")] {
+            assert_eq!(name.parse::<SuppressionShape>().unwrap(), shape);
+            let param = HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 20, 4).with_min_distance(18, shape);
+            let result = find_top_n(param).unwrap();
+            assert_eq!(result.len(), 4);
+            for (i, a) in result.iter().enumerate() {
+                assert_eq!(a.rank, i + 1);
+                for b in &result[i + 1..] {
+                    let (dx, dy) = (a.x.abs_diff(b.x), a.y.abs_diff(b.y));
+                    let distance = match shape {
+                        SuppressionShape::Circle => ((dx * dx + dy * dy) as f64).sqrt(),
+                        SuppressionShape::Rect => dx.max(dy) as f64,
+                    };
+                    assert!(distance >= 18.0, "{:?}", result);
+                }
+            }
+            // 挨在一起的两个方块都要找到
+            for x in [118, 138] {
+                assert!(result.iter().any(|p| p.x.abs_diff(x) <= 3 && p.y.abs_diff(48) <= 3), "{:?}", result);
+            }
+        }
+        assert!("triangle".parse::<SuppressionShape>().is_err());
+    }
+
+    /// 灰色底图上画一个中心在`(cx, cy)`的高斯亮斑, 中心可以是小数
+    fn gaussian_blob(cx: f64, cy: f64, sigma: f64) -> (DynamicImage, DynamicImage) {
+        let mut bg_image = DynamicImage::new_rgba8(80, 60);
+        let mut cg_image = DynamicImage::new_rgba8(80, 60);
+        for y in 0..60u32 {
+            for x in 0..80u32 {
+                let r2 = (x as f64 - cx).powi(2) + (y as f64 - cy).powi(2);
+                let value = 100 + (150.0 * (-r2 / (2.0 * sigma * sigma)).exp()).round() as u8;
+                bg_image.put_pixel(x, y, Rgba([100, 100, 100, 255]));
+                cg_image.put_pixel(x, y, Rgba([value, value, value, 255]));
+            }
+        }
+        (bg_image, cg_image)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_subpixel() {
+        // 中心在不同小数位置的高斯亮斑, 比较整数坐标和细化之后的误差
+        let (mut integer_error, mut refined_error, mut max_refined, mut count) = (0.0, 0.0, 0.0f64, 0.0);
+        for sigma in [2.0, 4.0] {
+            for ox in [0.0, 0.25, 0.5, 0.75] {
+                for oy in [0.0, 0.3, 0.6] {
+                    let (cx, cy) = (37.0 + ox, 28.0 + oy);
+                    let (bg_image, cg_image) = gaussian_blob(cx, cy, sigma);
+                    let param = HilltopParamAndResult::new(bg_image, cg_image, 16, 1).with_subpixel(true);
+                    let point = find_top_n(param).unwrap()[0];
+                    integer_error += ((point.x as f64 - cx).powi(2) + (point.y as f64 - cy).powi(2)).sqrt();
+                    let refined = ((point.fx - cx).powi(2) + (point.fy - cy).powi(2)).sqrt();
+                    refined_error += refined;
+                    max_refined = max_refined.max(refined);
+                    count += 1.0;
+                }
+            }
+        }
+        assert!(integer_error / count > 0.3, "{}", integer_error / count);
+        assert!(refined_error / count < 0.02, "{}", refined_error / count);
+        assert!(max_refined < 0.05, "{}", max_refined);
+
+        // 不开启的时候小数坐标就是整数坐标
+        let (bg_image, cg_image) = gaussian_blob(37.5, 28.5, 3.0);
+        let point = find_top_n(HilltopParamAndResult::new(bg_image, cg_image, 16, 1)).unwrap()[0];
+        assert_eq!((point.fx, point.fy), (point.x as f64, point.y as f64));
+    }
+
+    /// 带噪声的灰色底图, 挑战图再加一点点噪声, 在`(cx, cy)`画一个边长`size`的白色方块, 方块可以超出图片
+    fn noisy_target(width: u32, height: u32, seed: u64, cx: u32, cy: u32, size: u32) -> (DynamicImage, DynamicImage) {
+        let mut state = seed;
+        let mut noise = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 59) as u8
+        };
+        let mut bg_image = DynamicImage::new_rgba8(width, height);
+        let mut cg_image = DynamicImage::new_rgba8(width, height);
+        let half = size / 2;
+        for y in 0..height {
+            for x in 0..width {
+                let base = 100 + noise();
+                bg_image.put_pixel(x, y, Rgba([base, base, base, 255]));
+                let inside = x + half >= cx && x <= cx + half && y + half >= cy && y <= cy + half;
+                let pixel = if inside { Rgba([255, 255, 255, 255]) } else { Rgba([base, base + noise() / 8, base, 255]) };
+                cg_image.put_pixel(x, y, pixel);
+            }
+        }
+        (bg_image, cg_image)
+    }
+
+    /// 靠边的坐标出现的概率要高一些
+    fn border_coordinate(len: u32) -> impl Strategy<Value = u32> {
+        prop_oneof![Just(0), Just(1), Just(len - 1), Just(len - 2), 0..len]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(128))]
+
+        #[test]
+        fn prop_peaks_at_borders(
+            (width, height, cx, cy) in (12u32..90, 12u32..90)
+                .prop_flat_map(|(w, h)| (Just(w), Just(h), border_coordinate(w), border_coordinate(h))),
+            seed in any::<u64>(),
+            ch_size in 1u32..40,
+            top_n in 1usize..5,
+            min_distance in proptest::option::of(1usize..30),
+            subpixel in any::<bool>(),
+        ) {
+            let (bg_image, cg_image) = noisy_target(width, height, seed, cx, cy, ch_size);
+            let mut param = HilltopParamAndResult::new(bg_image, cg_image, ch_size, top_n).with_subpixel(subpixel);
+            if let Some(min_distance) = min_distance {
+                param = param.with_min_distance(min_distance, SuppressionShape::Circle);
+            }
+            let result = find_top_n(param).unwrap();
+            prop_assert!(!result.is_empty() && result.len() <= top_n);
+            if min_distance.is_none() {
+                prop_assert_eq!(result.len(), top_n);
+            }
+            for point in &result {
+                let (left, top, right, bottom) = point.bbox;
+                prop_assert!(point.x < width as usize && point.y < height as usize, "{:?}", point);
+                prop_assert!(left <= point.x && point.x <= right && right < width as usize, "{:?}", point);
+                prop_assert!(top <= point.y && point.y <= bottom && bottom < height as usize, "{:?}", point);
+                prop_assert!((point.fx - point.x as f64).abs() <= 1.0 && (point.fy - point.y as f64).abs() <= 1.0, "{:?}", point);
+                prop_assert!(point.fx >= 0.0 && point.fx <= (width - 1) as f64 && point.fy >= 0.0 && point.fy <= (height - 1) as f64, "{:?}", point);
+            }
+            // 最明显的目标一定是白色方块; 太小的目标在金字塔最右/最下那一格不完整的时候可能被噪声盖过去
+            let first = &result[0];
+            if ch_size >= 8 {
+                prop_assert!(first.x.abs_diff(cx as usize) <= ch_size as usize && first.y.abs_diff(cy as usize) <= ch_size as usize,
+                             "{:?} target ({}, {})", first, cx, cy);
+            }
+        }
+    }
+
+    #[test]
+    fn test_debug_artifacts() {
+        let (bg_image, cg_image) = load_images();
+        let param = HilltopParamAndResult::new(bg_image.clone(), cg_image.clone(), 40, 3);
+        let (points, debug) = find_top_n_with_debug(param).unwrap();
+        // 调试模式不影响结果
+        assert_eq!(points, find_top_n(HilltopParamAndResult::new(bg_image, cg_image, 40, 3)).unwrap());
+
+        let kinds: Vec<(&str, &str)> = debug.artifacts.iter().map(|a| (a.name.as_str(), a.kind)).collect();
+        // 300x150的图金字塔有60x30, 12x6, 3x2三层, 最后一个山峰之后不再削峰
+        assert_eq!(kinds, vec![
+            ("diff", "diff"), ("pyramid_1", "pyramid"), ("pyramid_2", "pyramid"), ("pyramid_3", "pyramid"),
+            ("peak_1_short_curt_mountain", "short_curt_mountain"), ("peak_1_suppression", "suppression"),
+            ("peak_2_short_curt_mountain", "short_curt_mountain"), ("peak_2_suppression", "suppression"),
+            ("peak_3_short_curt_mountain", "short_curt_mountain"),
+        ]);
+        assert_eq!(debug.artifacts[0].image.dimensions(), (300, 150));
+        assert_eq!(debug.artifacts[3].image.dimensions(), (3, 2));
+
+        // 削峰的区域在山峰附近
+        let suppression = debug.artifacts[5].image.to_luma8();
+        let (x, y) = (points[0].x as u32, points[0].y as u32);
+        assert!(suppression.get_pixel(x, y)[0] > 0);
+        assert_eq!(suppression.get_pixel((x + 100) % 300, y)[0], 0);
+
+        let temp = test_util::temp_dir();
+        let dir = temp.path().join("debug");
+        debug.write_to_dir(&dir).unwrap();
+        let manifest = std::fs::read_to_string(dir.join("manifest.json")).unwrap();
+        let manifest = rustc_serialize::json::Json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["artifacts"].as_array().unwrap().len(), 9);
+        assert_eq!(manifest["points"][0]["x"].as_u64(), Some(points[0].x as u64));
+        assert!(dir.join("pyramid_2.png").exists());
+    }
+}
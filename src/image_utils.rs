@@ -1,9 +1,144 @@
-use image::{Rgba};
+use std::ops::{Index, IndexMut};
+
+use image::{Rgba, DynamicImage};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use crate::error::{Error, Result};
+
+/// `rgb_diff`的最大值
+pub const MAX_RGB_DIFF: i32 = 255 * 3;
 
 pub fn rgb_diff(left: Rgba<u8>, right: Rgba<u8>) -> i32 {
     (left[0] as i32 - right[0] as i32).abs() + (left[1] as i32 - right[1] as i32).abs() + (left[2] as i32 - right[2] as i32).abs()
 }
 
+/// base64字符串 -> 图片
+pub fn decode_b64_image(input: &str) -> Result<DynamicImage> {
+    let data = base64::decode(input.trim())?;
+    image::load_from_memory(&data).map_err(Error::UnsupportedImage)
+}
+
+/// 图片 -> png格式的base64字符串
+#[cfg(any(feature = "python", test))]
+pub fn encode_png_b64(img: &DynamicImage) -> Result<String> {
+    let mut buf = vec![];
+    img.write_to(&mut buf, image::ImageOutputFormat::Png).map_err(Error::Encode)?;
+    Ok(base64::encode(&buf))
+}
+
+/// 按行连续存储的二维数组, 用`grid[(x, y)]`访问
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grid<T> {
+    width: usize,
+    height: usize,
+    data: Vec<T>,
+}
+
+impl<T: Clone> Grid<T> {
+    pub fn new(width: usize, height: usize, fill: T) -> Grid<T> {
+        Grid { width, height, data: vec![fill; width * height] }
+    }
+}
+
+impl<T: Send> Grid<T> {
+    /// 逐点调用`f(x, y)`生成, 开启`parallel`特性时多线程计算, 结果和单线程一致
+    pub fn from_fn<F>(width: usize, height: usize, f: F) -> Grid<T>
+        where F: Fn(usize, usize) -> T + Sync + Send {
+        #[cfg(feature = "parallel")]
+        let data = (0..width * height).into_par_iter().map(|i| f(i % width, i / width)).collect();
+        #[cfg(not(feature = "parallel"))]
+        let data = (0..width * height).map(|i| f(i % width, i / width)).collect();
+        Grid { width, height, data }
+    }
+
+    /// 对`[start_y, end_y]`之间的每一行调用`f(y, row)`, 开启`parallel`特性时按行并行
+    pub fn for_each_row_mut<F>(&mut self, start_y: usize, end_y: usize, f: F)
+        where F: Fn(usize, &mut [T]) + Sync + Send {
+        if start_y > end_y || self.width == 0 {
+            return;
+        }
+        let rows = &mut self.data[start_y * self.width..(end_y + 1) * self.width];
+        #[cfg(feature = "parallel")]
+        rows.par_chunks_mut(self.width).enumerate().for_each(|(k, row)| f(start_y + k, row));
+        #[cfg(not(feature = "parallel"))]
+        rows.chunks_mut(self.width).enumerate().for_each(|(k, row)| f(start_y + k, row));
+    }
+}
+
+impl<T> Grid<T> {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// 一整行的数据
+    pub fn row(&self, y: usize) -> &[T] {
+        &self.data[y * self.width..(y + 1) * self.width]
+    }
+}
+
+impl<T> Index<(usize, usize)> for Grid<T> {
+    type Output = T;
+
+    #[inline]
+    fn index(&self, (x, y): (usize, usize)) -> &T {
+        // 越界的x会读到下一行, 切片自己的边界检查发现不了
+        debug_assert!(x < self.width && y < self.height, "({}, {}) out of {}x{}", x, y, self.width, self.height);
+        &self.data[y * self.width + x]
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Grid<T> {
+    #[inline]
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut T {
+        debug_assert!(x < self.width && y < self.height, "({}, {}) out of {}x{}", x, y, self.width, self.height);
+        &mut self.data[y * self.width + x]
+    }
+}
+
+/// 积分图(summed-area table), 任意矩形区域求和都是O(1)
+pub struct IntegralImage {
+    width: usize,
+    height: usize,
+    /// (width + 1) * (height + 1), 第0行和第0列都是0
+    data: Vec<u64>,
+}
+
+impl IntegralImage {
+    pub fn new<T: Copy + Into<u64>>(grid: &Grid<T>) -> IntegralImage {
+        let (width, height) = (grid.width(), grid.height());
+        let stride = width + 1;
+        let mut data = vec![0u64; stride * (height + 1)];
+        for y in 0..height {
+            let mut row_sum = 0u64;
+            for (x, val) in grid.row(y).iter().enumerate() {
+                row_sum += (*val).into();
+                data[(y + 1) * stride + x + 1] = data[y * stride + x + 1] + row_sum;
+            }
+        }
+        IntegralImage { width, height, data }
+    }
+
+    /// 闭区间`[x0, x1] x [y0, y1]`的和, 超出范围的部分会被裁掉, 空区域返回0
+    #[inline]
+    pub fn sum(&self, x0: usize, y0: usize, x1: usize, y1: usize) -> u64 {
+        if self.width == 0 || self.height == 0 {
+            return 0;
+        }
+        let x1 = x1.min(self.width - 1);
+        let y1 = y1.min(self.height - 1);
+        if x0 > x1 || y0 > y1 {
+            return 0;
+        }
+        let stride = self.width + 1;
+        self.data[(y1 + 1) * stride + x1 + 1] + self.data[y0 * stride + x0]
+            - self.data[y0 * stride + x1 + 1] - self.data[(y1 + 1) * stride + x0]
+    }
+}
+
 // pub fn mask_merge(rgb_left: i32, rgb_right: i32, left_ratio: f32) -> i32 {
 //     let r: u32 = ((((rgb_left as u32) >> 24) & 0xFF) as f32 * left_ratio + ((rgb_right as u32) >> 24) as f32 * (1.0 - left_ratio)) as u32;
 //     let g: u32 = ((((rgb_left as u32) >> 16) & 0xFF) as f32 * left_ratio + ((rgb_right as u32) >> 16) as f32 * (1.0 - left_ratio)) as u32;
@@ -15,9 +150,58 @@ pub fn rgb_diff(left: Rgba<u8>, right: Rgba<u8>) -> i32 {
 
 #[cfg(test)]
 mod test {
+    use crate::error::Error;
+    use crate::image_utils::{decode_b64_image, encode_png_b64, Grid, IntegralImage};
+
     #[test]
     fn test_rgb_diff() {}
 
     #[test]
     fn test_mark_merge() {}
-}
\ No newline at end of file
+
+    #[test]
+    fn test_integral_image() {
+        let mut grid = Grid::new(7, 5, 0u32);
+        for y in 0..5 {
+            for x in 0..7 {
+                grid[(x, y)] = (x * 10 + y) as u32;
+            }
+        }
+        let integral = IntegralImage::new(&grid);
+        for (x0, y0, x1, y1) in [(0, 0, 6, 4), (2, 1, 4, 3), (3, 3, 3, 3), (5, 2, 20, 20)] {
+            let mut expected = 0u64;
+            for y in y0..=y1.min(4) {
+                for x in x0..=x1.min(6) {
+                    expected += grid[(x, y)] as u64;
+                }
+            }
+            assert_eq!(integral.sum(x0, y0, x1, y1), expected);
+        }
+        assert_eq!(integral.sum(4, 0, 3, 4), 0);
+        assert_eq!(integral.sum(7, 0, 9, 4), 0);
+    }
+
+    #[test]
+    fn test_grid_from_fn() {
+        let mut grid = Grid::from_fn(5, 3, |x, y| x * 10 + y);
+        assert_eq!(grid[(4, 2)], 42);
+        assert_eq!(grid.row(1), &[1, 11, 21, 31, 41]);
+        grid.for_each_row_mut(1, 2, |y, row| row.iter_mut().for_each(|val| *val += y * 100));
+        assert_eq!(grid.row(0), &[0, 10, 20, 30, 40]);
+        assert_eq!(grid.row(2), &[202, 212, 222, 232, 242]);
+    }
+
+    #[test]
+    fn test_b64_round_trip() {
+        let img = image::open("./src/images/0.jpg").unwrap();
+        let encoded = encode_png_b64(&img).unwrap();
+        let decoded = decode_b64_image(&encoded).unwrap();
+        assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(matches!(decode_b64_image("not base64!"), Err(Error::InvalidBase64(_))));
+        assert!(matches!(decode_b64_image(&base64::encode(b"plain text")), Err(Error::UnsupportedImage(_))));
+    }
+}
@@ -0,0 +1,195 @@
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, RgbImage, RgbaImage};
+#[cfg(feature = "python")]
+use pyo3::{buffer::PyBuffer, exceptions::PyTypeError, prelude::*, PyNativeType};
+#[cfg(feature = "python")]
+use pyo3::types::{PyByteArray, PyBytes, PyString};
+
+use crate::error::{Error, Result};
+use crate::image_utils::decode_b64_image;
+
+/// Python侧传进来的图片, 转换成不依赖GIL的Rust数据, 之后再统一解码成`DynamicImage`
+///
+/// 支持: `bytes`/`bytearray`(编码后的图片文件内容), 文件路径(`str`或`os.PathLike`),
+/// base64字符串(可以带`data:image/png;base64,`前缀), 以及HxWx3/HxWx4的uint8数组(numpy等实现了buffer协议的对象)。
+/// 数组按RGB(A)顺序解释, OpenCV读出来的BGR数组需要先`cv2.cvtColor`转换一下。
+#[derive(Clone, Debug)]
+pub enum ImageSource {
+    Bytes(Vec<u8>),
+    Path(PathBuf),
+    Base64(String),
+    Pixels { width: u32, height: u32, channels: usize, data: Vec<u8> },
+}
+
+/// 常见的图片扩展名, 小写
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "bmp", "webp", "tif", "tiff", "ico"];
+
+/// 扩展名是不是常见的图片格式(png/jpg/gif/bmp/webp/tiff/ico等), 不区分大小写
+pub fn has_image_extension(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+impl ImageSource {
+    /// 字符串输入: data uri / 文件路径 / base64
+    ///
+    /// 存在的文件, 或者带图片扩展名的字符串(文件不存在的时候报文件不存在)当作路径, 其他的都按base64解码,
+    /// 所以URL安全的base64、中间带空格的base64报的是base64错误
+    pub fn from_text(text: &str) -> ImageSource {
+        let text = text.trim();
+        if let Some(rest) = text.strip_prefix("data:") {
+            let payload = match rest.find("base64,") {
+                Some(idx) => &rest[idx + "base64,".len()..],
+                None => rest,
+            };
+            return ImageSource::Base64(payload.to_string());
+        }
+        let path = Path::new(text);
+        if path.is_file() || has_image_extension(path) {
+            return ImageSource::Path(path.to_path_buf());
+        }
+        ImageSource::Base64(text.to_string())
+    }
+
+    pub fn decode(self) -> Result<DynamicImage> {
+        match self {
+            ImageSource::Bytes(data) => image::load_from_memory(&data).map_err(Error::UnsupportedImage),
+            ImageSource::Path(path) => {
+                let data = std::fs::read(&path)
+                    .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                image::load_from_memory(&data).map_err(Error::UnsupportedImage)
+            }
+            ImageSource::Base64(text) => decode_b64_image(&text),
+            ImageSource::Pixels { width, height, channels, data } => {
+                let img = match channels {
+                    3 => RgbImage::from_raw(width, height, data).map(DynamicImage::ImageRgb8),
+                    4 => RgbaImage::from_raw(width, height, data).map(DynamicImage::ImageRgba8),
+                    _ => None,
+                };
+                img.ok_or_else(|| Error::UnsupportedArray(format!("cannot build {}x{}x{} image from array", height, width, channels)))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "python")]
+impl ImageSource {
+    fn from_buffer(py: Python, buf: PyBuffer<u8>) -> PyResult<ImageSource> {
+        let shape = buf.shape().to_vec();
+        if shape.len() != 3 || !(shape[2] == 3 || shape[2] == 4) {
+            return Err(Error::UnsupportedArray(format!("expected HxWx3 or HxWx4 uint8 array, got shape {:?}", shape)).into());
+        }
+        let data = buf.to_vec(py)?;
+        buf.release(py);
+        Ok(ImageSource::Pixels {
+            width: shape[1] as u32,
+            height: shape[0] as u32,
+            channels: shape[2],
+            data,
+        })
+    }
+}
+
+#[cfg(feature = "python")]
+impl<'source> FromPyObject<'source> for ImageSource {
+    fn extract(ob: &'source PyAny) -> PyResult<Self> {
+        if let Ok(bytes) = ob.downcast::<PyBytes>() {
+            return Ok(ImageSource::Bytes(bytes.as_bytes().to_vec()));
+        }
+        if let Ok(bytes) = ob.downcast::<PyByteArray>() {
+            return Ok(ImageSource::Bytes(bytes.to_vec()));
+        }
+        if let Ok(text) = ob.downcast::<PyString>() {
+            return Ok(ImageSource::from_text(text.to_str()?));
+        }
+        // pathlib.Path 之类的对象
+        if ob.hasattr("__fspath__")? {
+            let path: PathBuf = ob.call_method0("__fspath__")?.extract()?;
+            return Ok(ImageSource::Path(path));
+        }
+        // numpy数组等实现了buffer协议的对象
+        if let Ok(buf) = PyBuffer::<u8>::get(ob) {
+            return ImageSource::from_buffer(ob.py(), buf);
+        }
+        Err(PyTypeError::new_err(format!(
+            "unsupported image input type: {}, expected bytes, bytearray, str, path or uint8 array",
+            ob.get_type().name()?
+        )))
+    }
+}
+
+/// 批量解码
+#[cfg(feature = "python")]
+pub fn decode_all(sources: Vec<ImageSource>) -> Result<Vec<DynamicImage>> {
+    sources.into_iter().map(ImageSource::decode).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::input::ImageSource;
+    use image::GenericImageView;
+
+    #[test]
+    fn test_from_text() {
+        assert!(matches!(ImageSource::from_text("./src/images/0.jpg"), ImageSource::Path(_)));
+        match ImageSource::from_text("data:image/png;base64,AAAA") {
+            ImageSource::Base64(text) => assert_eq!(text, "AAAA"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(ImageSource::from_text("iVBORw0KGgo="), ImageSource::Base64(_)));
+        assert!(matches!(ImageSource::from_text("iVBORw0K/Ggo+"), ImageSource::Base64(_)));
+    }
+
+    #[test]
+    fn test_missing_path() {
+        // 带图片扩展名的路径写错了报文件不存在, 而不是base64错误
+        for text in ["bg.png", "./images/missing.JPG", "C:\\captcha\\bg.jpeg"] {
+            match ImageSource::from_text(text).decode() {
+                Err(Error::Io(e)) => {
+                    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+                    assert!(e.to_string().contains(text));
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_base64() {
+        // URL安全的base64、带空格或者tab的base64、data uri里多了空白都不是路径, 报base64错误
+        let data = base64::encode(std::fs::read("./src/images/0.jpg").unwrap());
+        let url_safe = data.replace('+', "-").replace('/', "_");
+        let spaced = format!("{} {}", &data[..100], &data[100..]);
+        let tabbed = format!("{}\t{}", &data[..100], &data[100..]);
+        let uri = format!("data:image/jpeg;base64,{} {}", &data[..100], &data[100..]);
+        for text in [url_safe, spaced, tabbed, uri, "bg.pgn".to_string(), "./images/missing".to_string()] {
+            let source = ImageSource::from_text(&text);
+            assert!(!matches!(source, ImageSource::Path(_)), "{}", &text[..20.min(text.len())]);
+            assert!(matches!(source.decode(), Err(Error::InvalidBase64(_))), "{}", &text[..20.min(text.len())]);
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let data = std::fs::read("./src/images/0.jpg").unwrap();
+        let from_bytes = ImageSource::Bytes(data.clone()).decode().unwrap();
+        let from_path = ImageSource::from_text("./src/images/0.jpg").decode().unwrap();
+        let uri = format!("data:image/jpeg;base64,{}", base64::encode(&data));
+        let from_uri = ImageSource::from_text(&uri).decode().unwrap();
+        assert_eq!(from_bytes.to_rgba8(), from_path.to_rgba8());
+        assert_eq!(from_bytes.to_rgba8(), from_uri.to_rgba8());
+
+        let rgb = from_bytes.to_rgb8();
+        let (width, height) = from_bytes.dimensions();
+        let from_pixels = ImageSource::Pixels { width, height, channels: 3, data: rgb.clone().into_raw() }.decode().unwrap();
+        assert_eq!(from_pixels.to_rgb8(), rgb);
+    }
+
+    #[test]
+    fn test_decode_bad_pixels() {
+        let source = ImageSource::Pixels { width: 4, height: 4, channels: 3, data: vec![0; 10] };
+        assert!(matches!(source.decode(), Err(Error::UnsupportedArray(_))));
+    }
+}
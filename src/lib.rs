@@ -1,51 +1,86 @@
-use pyo3::{prelude::*};
+//! 滑块和点选验证码的图片坐标计算
+//!
+//! 默认开启的`python`特性会编译出Python扩展模块`image_magic`; 只在Rust里使用的话关掉默认特性,
+//! 不需要安装Python:
+//!
+//! ```toml
+//! image-magic = { version = "0.1", default-features = false }
+//! ```
+//!
+//! 典型的流程是先用同一张底图的多张挑战图合成背景, 再用背景和新的挑战图找差异最大的位置:
+//!
+//! ```
+//! use image_magic::{find_peaks, merge_backgrounds, HilltopParamAndResult, MergeOptions};
+//!
+//! let samples: Vec<_> = (0..4).map(|i| image::open(format!("src/images/{}.jpg", i)).unwrap()).collect();
+//! let background = merge_backgrounds(&samples, &MergeOptions::default())?;
+//! let params = HilltopParamAndResult::new(background, samples[0].clone(), 40, 3);
+//! for point in find_peaks(params)? {
+//!     println!("#{} ({}, {}) weight={}", point.rank, point.x, point.y, point.weight);
+//! }
+//! # Ok::<(), image_magic::Error>(())
+//! ```
 
+use image::DynamicImage;
+
+mod error;
+mod input;
+#[cfg(feature = "python")]
+mod py_future;
+#[cfg(feature = "python")]
+mod python;
 mod image_utils;
+mod debug_artifacts;
+mod color_diff;
 mod image_avg_merger;
 mod image_hill_top_v2;
+mod background_accumulator;
+mod image_cluster;
+mod background_library;
+mod slider;
+mod rotation;
+mod click_order;
+mod annotate;
+#[cfg(test)]
+mod test_util;
 
-use base64::{decode};
-use pyo3::types::{PyList, PyString};
-use crate::image_hill_top_v2::{HilltopParamAndResult, Point};
+pub use crate::annotate::annotate;
+pub use crate::background_accumulator::BackgroundAccumulator;
+pub use crate::background_library::{BackgroundLibrary, LibraryMatch};
+pub use crate::click_order::{order_by_hint, split_hint, IconRect};
+pub use crate::color_diff::DiffMetric;
+pub use crate::debug_artifacts::{Artifact, DebugArtifacts};
+pub use crate::error::{Error, Result};
+pub use crate::image_avg_merger::{MergeOptions, MergeStatistic};
+pub use crate::image_cluster::{BackgroundGroup, DEFAULT_MAX_DISTANCE};
+pub use crate::image_hill_top_v2::{BoundingBox, HilltopParamAndResult, Point, SizeStrategy, StopRule, SuppressionShape};
+pub use crate::input::{has_image_extension, ImageSource};
+pub use crate::rotation::{rotation_from_composite, rotation_from_parts, RotationMatch};
+pub use crate::slider::{find_gap_by_shadow, slider_offset, GapCandidate, ShadowOptions, SliderMatch};
 use image_hill_top_v2::{self as x};
 
-#[pyfunction]
-pub fn demo_py_function() -> PyResult<String> {
-    return PyResult::Ok(String::from("hello rust ffi!"));
+/// 把同一张底图的多张挑战图合成背景, 每个像素点按`options`里的统计量从所有图片里取值
+///
+/// 图片尺寸不一致的时候统一缩放到平均尺寸, `images`为空返回`Error::EmptyInput`
+pub fn merge_backgrounds(images: &[DynamicImage], options: &MergeOptions) -> Result<DynamicImage> {
+    image_avg_merger::avg(images, options)
 }
 
-#[pyfunction]
-pub fn avg_b64(input: &PyList) -> PyResult<String> {
-    let mut image_input = vec![];
-    for src in input {
-        let target = decode(src.to_string()).unwrap();
-        let img = image::load_from_memory(&target).unwrap();
-        image_input.push(img);
-    }
-    let result = image_avg_merger::avg(&image_input);
-    let mut buf = vec![];
-    result.write_to(&mut buf, image::ImageOutputFormat::Png).unwrap();
-    return PyResult::Ok(base64::encode(&buf));
+/// 混在一起的图片先按感知哈希分组, 每组单独合成背景, 结果按组的大小从大到小排列
+///
+/// 两张图片哈希的汉明距离(64位)不超过`max_distance`就认为是同一张底图, 一般用`DEFAULT_MAX_DISTANCE`
+pub fn group_backgrounds(images: &[DynamicImage], max_distance: u32, options: &MergeOptions) -> Result<Vec<BackgroundGroup>> {
+    image_cluster::group_avg(images, max_distance, options)
 }
 
-#[pyfunction]
-pub fn top_n(bg_image: &PyString, cg_image: &PyString, ch_size: usize, top_n: usize) -> PyResult<Vec<Point>> {
-    let target = decode(bg_image.to_string()).unwrap();
-    let bg_image = image::load_from_memory(&target).unwrap();
-    let target = decode(cg_image.to_string()).unwrap();
-    let cg_image = image::load_from_memory(&target).unwrap();
-    let result = HilltopParamAndResult::new(bg_image, cg_image, ch_size as u32, top_n);
-    let result = x::find_top_n(result);
-    return PyResult::Ok(result);
+/// 按`params`找挑战图和背景差异最大的`top_n`个位置, 结果按权重从高到低排列
+pub fn find_peaks(params: HilltopParamAndResult) -> Result<Vec<Point>> {
+    x::find_top_n(params)
 }
 
-#[pymodule]
-fn image_magic(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(demo_py_function, m)?)?;
-    m.add_function(wrap_pyfunction!(avg_b64, m)?)?;
-    m.add_function(wrap_pyfunction!(top_n, m)?)?;
-    m.add_class::<Point>()?;
-    Ok(())
+/// 和`find_peaks`一样, 另外返回差值热力图、金字塔等中间结果, 排查识别失败用
+pub fn find_peaks_with_debug(params: HilltopParamAndResult) -> Result<(Vec<Point>, DebugArtifacts)> {
+    x::find_top_n_with_debug(params)
 }
 
 #[cfg(test)]
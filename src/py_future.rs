@@ -0,0 +1,142 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use lazy_static::lazy_static;
+use pyo3::panic::PanicException;
+use pyo3::prelude::*;
+
+use crate::error::Result;
+
+type Task = Box<dyn FnOnce() + Send + 'static>;
+
+lazy_static! {
+    /// 固定`pool_size()`个工作线程, 第一次提交任务的时候才创建, 多出来的任务在队列里排队。
+    /// 开启`parallel`特性时也不用rayon的线程池: 任务要等GIL, 占着rayon的线程等会拖住所有的并行计算
+    static ref WORKERS: Mutex<mpsc::Sender<Task>> = {
+        let (sender, receiver) = mpsc::channel::<Task>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..pool_size() {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new().name(format!("image-magic-worker-{}", i)).spawn(move || loop {
+                // 取到任务之后马上释放锁, 不然同一时间只有一个线程在算
+                let task = receiver.lock().unwrap().recv();
+                match task {
+                    Ok(task) => task(),
+                    Err(_) => break,
+                }
+            }).expect("failed to spawn worker thread");
+        }
+        Mutex::new(sender)
+    };
+}
+
+/// 同时执行的任务数上限, CPU核数
+fn pool_size() -> usize {
+    thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+/// 把任务交给线程池, 不阻塞
+fn execute(task: Task) {
+    WORKERS.lock().unwrap().send(task).expect("worker pool is gone");
+}
+
+/// 在线程池里执行`job`, 立刻返回一个`concurrent.futures.Future`
+///
+/// 计算过程不持有GIL, asyncio里可以直接`await asyncio.wrap_future(fut)`。
+/// 同时最多`pool_size()`个任务在算, 一次提交上万个任务也只是排队, 不会创建上万个线程;
+/// 任务panic的话`Future`里是`PanicException`, 不影响线程池
+pub(crate) fn spawn<F, T>(py: Python, job: F) -> PyResult<PyObject>
+    where F: FnOnce() -> Result<T> + Send + 'static,
+          T: IntoPy<PyObject> + Send + 'static {
+    let future: PyObject = py.import("concurrent.futures")?.getattr("Future")?.call0()?.into();
+    let handle = future.clone_ref(py);
+    execute(Box::new(move || {
+        // 已经被取消的任务就不用算了
+        let should_run = Python::with_gil(|py| {
+            handle.call_method0(py, "set_running_or_notify_cancel")
+                .and_then(|ret| ret.extract::<bool>(py))
+                .unwrap_or(false)
+        });
+        if !should_run {
+            return;
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+        Python::with_gil(|py| {
+            let outcome = match result {
+                Ok(Ok(value)) => handle.call_method1(py, "set_result", (value.into_py(py),)),
+                Ok(Err(err)) => {
+                    let err: PyErr = err.into();
+                    handle.call_method1(py, "set_exception", (err.into_instance(py),))
+                }
+                Err(_) => {
+                    let err = PanicException::new_err("image_magic worker panicked");
+                    handle.call_method1(py, "set_exception", (err.into_instance(py),))
+                }
+            };
+            if let Err(err) = outcome {
+                err.print(py);
+            }
+        });
+    }));
+    Ok(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::py_future::{execute, pool_size};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    #[test]
+    fn test_pool_is_bounded() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = mpsc::channel();
+        let total = pool_size() * 3;
+        for _ in 0..total {
+            let (running, peak, sender) = (Arc::clone(&running), Arc::clone(&peak), sender.clone());
+            execute(Box::new(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                sender.send(()).unwrap();
+            }));
+        }
+        for _ in 0..total {
+            receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+        assert!(peak.load(Ordering::SeqCst) <= pool_size());
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_workers_leave_rayon_free() {
+        use rayon::prelude::*;
+
+        // 所有工作线程都卡住(比如在等GIL)的时候, rayon的并行计算还能照常跑
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(std::sync::Mutex::new(blocked));
+        let (started, wait_started) = mpsc::channel();
+        for _ in 0..pool_size() {
+            let (blocked, started) = (Arc::clone(&blocked), started.clone());
+            execute(Box::new(move || {
+                assert!(rayon::current_thread_index().is_none(), "async job ran on a rayon thread");
+                started.send(()).unwrap();
+                let _ = blocked.lock().unwrap().recv();
+            }));
+        }
+        for _ in 0..pool_size() {
+            wait_started.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+
+        let (done, wait_done) = mpsc::channel();
+        std::thread::spawn(move || done.send((0..10_000u64).into_par_iter().sum::<u64>()).unwrap());
+        assert_eq!(wait_done.recv_timeout(Duration::from_secs(10)).unwrap(), 49_995_000);
+        for _ in 0..pool_size() {
+            release.send(()).unwrap();
+        }
+    }
+}
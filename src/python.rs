@@ -0,0 +1,283 @@
+use image::DynamicImage;
+use pyo3::prelude::*;
+
+use crate::background_accumulator::BackgroundAccumulator;
+use crate::background_library::BackgroundLibrary;
+use crate::color_diff::DiffMetric;
+use crate::error::{self, Error};
+use crate::image_avg_merger::{self, MergeOptions};
+use crate::image_cluster::{self, BackgroundGroup, DEFAULT_MAX_DISTANCE};
+use crate::image_hill_top_v2::{self, BoundingBox, HilltopParamAndResult, Point, SizeStrategy, StopRule, SuppressionShape};
+use crate::image_utils::encode_png_b64;
+use crate::input::{self, ImageSource};
+use crate::slider::{self, ShadowOptions};
+use crate::{click_order, py_future, rotation};
+
+/// `top_n`里除了图片以外的参数
+struct TopNOptions {
+    ch_size: usize,
+    top_n: usize,
+    size_strategy: SizeStrategy,
+    metric: DiffMetric,
+    ignore: Vec<BoundingBox>,
+    ignore_mask: Option<ImageSource>,
+    roi: Option<BoundingBox>,
+    stop_rule: StopRule,
+    min_distance: Option<usize>,
+    suppression: SuppressionShape,
+    subpixel: bool,
+    debug_dir: Option<String>,
+}
+
+impl TopNOptions {
+    /// `top_n`和`top_n_async`的参数都在这里转换, 加新参数只需要改这里和两个函数的签名
+    #[allow(clippy::too_many_arguments)]
+    fn from_args(ch_size: usize, top_n: usize, size_strategy: &str, metric: &str, ignore: Option<Vec<BoundingBox>>,
+                 ignore_mask: Option<ImageSource>, roi: Option<BoundingBox>, min_peak_ratio: Option<f64>,
+                 min_avg_ratio: Option<f64>, min_distance: Option<usize>, suppression: &str, subpixel: bool,
+                 debug_dir: Option<String>) -> error::Result<TopNOptions> {
+        Ok(TopNOptions {
+            ch_size,
+            top_n,
+            size_strategy: size_strategy.parse()?,
+            metric: metric.parse()?,
+            ignore: ignore.unwrap_or_default(),
+            ignore_mask,
+            roi,
+            stop_rule: StopRule { min_peak_ratio, min_avg_ratio },
+            min_distance,
+            suppression: suppression.parse()?,
+            subpixel,
+            debug_dir,
+        })
+    }
+}
+
+fn run_top_n(bg_image: Option<ImageSource>, library: Option<BackgroundLibrary>, cg_image: ImageSource,
+             options: TopNOptions) -> error::Result<Vec<Point>> {
+    let cg_image = cg_image.decode()?;
+    let bg_image = match (bg_image, library) {
+        (Some(bg_image), _) => bg_image.decode()?,
+        (None, Some(library)) => library.find(&cg_image)
+            .ok_or_else(|| Error::NotFound("no matching background in library".to_string()))?
+            .background,
+        (None, None) => return Err(Error::InvalidParameter("either bg_image or library is required".to_string())),
+    };
+    let mut result = HilltopParamAndResult::new(bg_image, cg_image, options.ch_size as u32, options.top_n)
+        .with_size_strategy(options.size_strategy)
+        .with_diff_metric(options.metric)
+        .with_stop_rule(options.stop_rule)
+        .with_subpixel(options.subpixel);
+    for rect in options.ignore {
+        result = result.with_ignore_rect(rect);
+    }
+    if let Some(mask) = options.ignore_mask {
+        result = result.with_ignore_mask(mask.decode()?);
+    }
+    if let Some(roi) = options.roi {
+        result = result.with_roi(roi);
+    }
+    if let Some(min_distance) = options.min_distance {
+        result = result.with_min_distance(min_distance, options.suppression);
+    }
+    match options.debug_dir {
+        Some(debug_dir) => {
+            let (points, debug) = image_hill_top_v2::find_top_n_with_debug(result)?;
+            debug.write_to_dir(debug_dir)?;
+            Ok(points)
+        }
+        None => image_hill_top_v2::find_top_n(result),
+    }
+}
+
+#[pyfunction]
+pub fn demo_py_function() -> PyResult<String> {
+    PyResult::Ok(String::from("hello rust ffi!"))
+}
+
+fn run_avg(input: Vec<ImageSource>, options: MergeOptions) -> error::Result<String> {
+    let image_input = input::decode_all(input)?;
+    let result = image_avg_merger::avg(&image_input, &options)?;
+    encode_png_b64(&result)
+}
+
+/// 输入图片可以是bytes/bytearray/文件路径/base64字符串/uint8数组, 见`ImageSource`, 路径不存在抛`FileNotFoundError`;
+/// 数组按RGB(A)解释, OpenCV读出来的BGR数组必须先`cv2.cvtColor(img, cv2.COLOR_BGR2RGB)`转换, 否则颜色差异会算错
+///
+/// `options`: `MergeOptions`, 不传的话保留离均值最近的85%样本求平均
+#[pyfunction(input, options = "None")]
+pub fn avg_b64(py: Python, input: Vec<ImageSource>, options: Option<MergeOptions>) -> PyResult<String> {
+    let options = options.unwrap_or_default();
+    PyResult::Ok(py.allow_threads(move || run_avg(input, options))?)
+}
+
+/// `avg_b64`的异步版本, 返回`concurrent.futures.Future`
+///
+/// 所有`*_async`共用一个线程池, 同时最多CPU核数个任务在算, 多出来的排队; 开启`parallel`时任务内部的计算再用rayon并行
+#[pyfunction(input, options = "None")]
+pub fn avg_b64_async(py: Python, input: Vec<ImageSource>, options: Option<MergeOptions>) -> PyResult<PyObject> {
+    let options = options.unwrap_or_default();
+    py_future::spawn(py, move || run_avg(input, options))
+}
+
+/// 混在一起的图片先按感知哈希分组, 每组单独合成背景, 结果按组的大小从大到小排列
+///
+/// `max_distance`: 两张图片哈希的汉明距离(64位)不超过这个值就认为是同一张底图
+#[pyfunction(input, max_distance = "DEFAULT_MAX_DISTANCE", options = "None")]
+pub fn group_avg_b64(py: Python, input: Vec<ImageSource>, max_distance: u32, options: Option<MergeOptions>) -> PyResult<Vec<BackgroundGroup>> {
+    let options = options.unwrap_or_default();
+    PyResult::Ok(py.allow_threads(move || {
+        let image_input = input::decode_all(input)?;
+        image_cluster::group_avg(&image_input, max_distance, &options)
+    })?)
+}
+
+/// 在挑战图里找和底图差异最大的`top_n`个位置, 结果按权重从高到低排列
+///
+/// `size_strategy`: 底图和挑战图尺寸不一致时的处理方式, 可选`resize`/`crop`/`center`/`reject`
+///
+/// 前四个参数可以按位置传, 其他参数只能按名字传
+///
+/// `bg_image`: 底图, 可以是`None`; `library`: `BackgroundLibrary`, `bg_image`是`None`的时候从图库里找最匹配的背景,
+/// 找不到抛`NotFoundError`, `bg_image`是`None`又没传`library`抛`InvalidParameterError`
+///
+/// `metric`: 像素差异算法, 可选`l1`/`l2`/`cie76`/`ciede2000`/`luma`/`hue`
+///
+/// `ignore`: 忽略的矩形列表`[(left, top, right, bottom)]`, 水印、刷新按钮之类的位置;
+/// `ignore_mask`: 和挑战图一样大的掩码图片, 白色的像素忽略; `roi`: 只在这个矩形里找目标
+///
+/// `min_peak_ratio`/`min_avg_ratio`: 传了任意一个的话`top_n`只是上限, 山峰的权重低于第一个山峰的这个比例,
+/// 或者低于平均差值对应权重的这个倍数就停止, 只返回真正的目标
+///
+/// `min_distance`: 返回的点之间的最小距离, `suppression`: 按`circle`(欧氏距离)还是`rect`(横纵坐标差)算距离
+///
+/// `subpixel`: 亚像素细化, 小数坐标在`Point.fx`/`Point.fy`
+///
+/// `debug_dir`: 把差值热力图、金字塔每一层、每个山峰的削峰区域和缩略图山峰存成PNG写到这个目录,
+/// 另外写一份`manifest.json`说明每张图的含义
+#[pyfunction(bg_image, cg_image, ch_size, top_n, "*", size_strategy = "\"resize\"", library = "None", metric = "\"l1\"",
+             ignore = "None", ignore_mask = "None", roi = "None", min_peak_ratio = "None", min_avg_ratio = "None",
+             min_distance = "None", suppression = "\"circle\"", subpixel = "false",
+             debug_dir = "None")]
+#[allow(clippy::too_many_arguments)]
+pub fn top_n(py: Python, bg_image: Option<ImageSource>, cg_image: ImageSource, ch_size: usize, top_n: usize,
+             size_strategy: &str, library: Option<BackgroundLibrary>, metric: &str, ignore: Option<Vec<BoundingBox>>,
+             ignore_mask: Option<ImageSource>, roi: Option<BoundingBox>, min_peak_ratio: Option<f64>,
+             min_avg_ratio: Option<f64>, min_distance: Option<usize>, suppression: &str,
+             subpixel: bool, debug_dir: Option<String>) -> PyResult<Vec<Point>> {
+    let options = TopNOptions::from_args(ch_size, top_n, size_strategy, metric, ignore, ignore_mask, roi, min_peak_ratio,
+                                         min_avg_ratio, min_distance, suppression, subpixel, debug_dir)?;
+    PyResult::Ok(py.allow_threads(move || run_top_n(bg_image, library, cg_image, options))?)
+}
+
+/// `top_n`的异步版本, 返回`concurrent.futures.Future`, 和`avg_b64_async`共用线程池
+#[pyfunction(bg_image, cg_image, ch_size, top_n, "*", size_strategy = "\"resize\"", library = "None", metric = "\"l1\"",
+             ignore = "None", ignore_mask = "None", roi = "None", min_peak_ratio = "None", min_avg_ratio = "None",
+             min_distance = "None", suppression = "\"circle\"", subpixel = "false",
+             debug_dir = "None")]
+#[allow(clippy::too_many_arguments)]
+pub fn top_n_async(py: Python, bg_image: Option<ImageSource>, cg_image: ImageSource, ch_size: usize, top_n: usize,
+                   size_strategy: &str, library: Option<BackgroundLibrary>, metric: &str, ignore: Option<Vec<BoundingBox>>,
+                   ignore_mask: Option<ImageSource>, roi: Option<BoundingBox>, min_peak_ratio: Option<f64>,
+                   min_avg_ratio: Option<f64>, min_distance: Option<usize>, suppression: &str,
+                   subpixel: bool, debug_dir: Option<String>) -> PyResult<PyObject> {
+    let options = TopNOptions::from_args(ch_size, top_n, size_strategy, metric, ignore, ignore_mask, roi, min_peak_ratio,
+                                         min_avg_ratio, min_distance, suppression, subpixel, debug_dir)?;
+    py_future::spawn(py, move || run_top_n(bg_image, library, cg_image, options))
+}
+
+/// 滑块验证码: 在带缺口的底图里找拼图块(带alpha通道)的位置
+///
+/// 返回`(x, score)`, `x`是拼图块图片左边缘应该移动到的横坐标, `score`是0..1的匹配度
+#[pyfunction]
+pub fn slider_offset(py: Python, bg_image: ImageSource, piece_image: ImageSource) -> PyResult<(usize, f64)> {
+    PyResult::Ok(py.allow_threads(move || -> error::Result<(usize, f64)> {
+        let result = slider::slider_offset(&bg_image.decode()?, &piece_image.decode()?)?;
+        Ok((result.x, result.score))
+    })?)
+}
+
+/// 滑块验证码没有拼图块的时候, 只靠缺口的阴影和轮廓找位置
+///
+/// `size`: 缺口的大概边长, `band`: 缺口所在的纵向范围`(top, bottom)`
+///
+/// 返回按得分从高到低排列的`[(x, y, score)]`, `x`/`y`是缺口左上角
+#[pyfunction(bg_image, size, "*", band = "None", top_n = "3")]
+pub fn slider_gap_by_shadow(py: Python, bg_image: ImageSource, size: usize, band: Option<(usize, usize)>,
+                            top_n: usize) -> PyResult<Vec<(usize, usize, f64)>> {
+    PyResult::Ok(py.allow_threads(move || -> error::Result<Vec<(usize, usize, f64)>> {
+        let mut options = ShadowOptions::new(size).with_top_n(top_n);
+        if let Some((top, bottom)) = band {
+            options = options.with_band(top, bottom);
+        }
+        let candidates = slider::find_gap_by_shadow(&bg_image.decode()?, &options)?;
+        Ok(candidates.iter().map(|c| (c.x, c.y, c.score)).collect())
+    })?)
+}
+
+/// 旋转验证码: 估计内圈需要顺时针转多少度才能和外圈对齐
+///
+/// 只传`image`的时候当作内外圈合成在一起的图, 圆心在图片中心;
+/// 同时传了`background`的时候`image`是单独的圆盘(圆外透明), 放在背景的中心。
+/// `radius`: 内圈半径, 不传的话自动估计
+///
+/// 返回`(angle, score)`, `angle`单位是度, `score`是0..1的匹配度
+#[pyfunction(image, background = "None", "*", radius = "None")]
+pub fn rotation_angle(py: Python, image: ImageSource, background: Option<ImageSource>, radius: Option<f64>) -> PyResult<(f64, f64)> {
+    PyResult::Ok(py.allow_threads(move || -> error::Result<(f64, f64)> {
+        let image = image.decode()?;
+        let result = match background {
+            Some(background) => rotation::rotation_from_parts(&image, &background.decode()?, radius)?,
+            None => rotation::rotation_from_composite(&image, radius)?,
+        };
+        Ok((result.angle, result.score))
+    })?)
+}
+
+/// 点选验证码: 把`top_n`找到的点按提示图里图标的顺序排列
+///
+/// `hint_image`: 提示图, 图标从左到右排列; `points`: `top_n`的结果, 不能比图标少, 返回的`rank`是提示图里的顺序;
+/// `bg_image`: `top_n`用的底图, 传了的话用差值图匹配, 背景纹理复杂的时候更准
+#[pyfunction(hint_image, cg_image, points, ch_size, "*", bg_image = "None")]
+pub fn order_by_hint(py: Python, hint_image: ImageSource, cg_image: ImageSource, points: Vec<Point>, ch_size: u32,
+                     bg_image: Option<ImageSource>) -> PyResult<Vec<Point>> {
+    PyResult::Ok(py.allow_threads(move || -> error::Result<Vec<Point>> {
+        let background = bg_image.map(|bg_image| bg_image.decode()).transpose()?;
+        click_order::order_by_hint(&hint_image.decode()?, &cg_image.decode()?, background.as_ref(), &points, ch_size)
+    })?)
+}
+
+/// 把`top_n`的结果画在挑战图上, 返回png格式的base64字符串
+///
+/// 每个点画`bbox`框、带名次的圆点和权重, 超出图片的点抛`InvalidParameterError`;
+/// `bg_image`: 传了的话叠加一层半透明的差值图
+#[pyfunction(cg_image, points, "*", bg_image = "None")]
+pub fn annotate(py: Python, cg_image: ImageSource, points: Vec<Point>, bg_image: Option<ImageSource>) -> PyResult<String> {
+    PyResult::Ok(py.allow_threads(move || -> error::Result<String> {
+        let background = bg_image.map(|bg_image| bg_image.decode()).transpose()?;
+        let result = crate::annotate(&cg_image.decode()?, &points, background.as_ref())?;
+        encode_png_b64(&DynamicImage::ImageRgba8(result))
+    })?)
+}
+
+#[pymodule]
+fn image_magic(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(demo_py_function, m)?)?;
+    m.add_function(wrap_pyfunction!(avg_b64, m)?)?;
+    m.add_function(wrap_pyfunction!(avg_b64_async, m)?)?;
+    m.add_function(wrap_pyfunction!(group_avg_b64, m)?)?;
+    m.add_function(wrap_pyfunction!(top_n, m)?)?;
+    m.add_function(wrap_pyfunction!(top_n_async, m)?)?;
+    m.add_function(wrap_pyfunction!(slider_offset, m)?)?;
+    m.add_function(wrap_pyfunction!(slider_gap_by_shadow, m)?)?;
+    m.add_function(wrap_pyfunction!(rotation_angle, m)?)?;
+    m.add_function(wrap_pyfunction!(order_by_hint, m)?)?;
+    m.add_function(wrap_pyfunction!(annotate, m)?)?;
+    m.add_class::<Point>()?;
+    m.add_class::<MergeOptions>()?;
+    m.add_class::<BackgroundAccumulator>()?;
+    m.add_class::<BackgroundGroup>()?;
+    m.add_class::<BackgroundLibrary>()?;
+    error::register(py, m)?;
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use std::f64::consts::PI;
+
+use image::{DynamicImage, RgbaImage};
+
+use crate::error::{Error, Result};
+
+/// 一圈采样多少个角度, 也就是1度一个
+const ANGLE_BINS: usize = 360;
+/// 采样环离边界的距离, 避开边界上的抗锯齿和描边
+const BORDER_GAP: f64 = 2.0;
+
+/// 旋转验证码的识别结果
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RotationMatch {
+    /// 内圈需要顺时针旋转多少度才能和外圈对齐, 0..360
+    pub angle: f64,
+    /// 对齐之后内外两圈边缘颜色的相关系数, 0..1
+    pub score: f64,
+    /// 实际使用的内圈半径
+    pub radius: f64,
+}
+
+/// 双线性插值取一个点的RGB, 超出范围或者碰到透明像素返回None
+fn sample(img: &RgbaImage, x: f64, y: f64) -> Option<[f64; 3]> {
+    let (x0, y0) = (x.floor(), y.floor());
+    if x0 < 0.0 || y0 < 0.0 || x0 + 1.0 >= img.width() as f64 || y0 + 1.0 >= img.height() as f64 {
+        return None;
+    }
+    let (fx, fy) = (x - x0, y - y0);
+    let mut rgb = [0.0; 3];
+    for (dx, dy, weight) in [(0, 0, (1.0 - fx) * (1.0 - fy)), (1, 0, fx * (1.0 - fy)),
+                             (0, 1, (1.0 - fx) * fy), (1, 1, fx * fy)] {
+        let pixel = img.get_pixel(x0 as u32 + dx, y0 as u32 + dy);
+        if pixel[3] < 128 {
+            return None;
+        }
+        for (c, val) in rgb.iter_mut().enumerate() {
+            *val += pixel[c] as f64 * weight;
+        }
+    }
+    Some(rgb)
+}
+
+/// 以(cx, cy)为圆心, 半径`[r0, r1]`的圆环上每个角度的平均颜色。
+/// 角度从x轴正方向开始顺时针(图片坐标y轴朝下), 整个角度都没有有效像素的话返回None
+fn radial_profile(img: &RgbaImage, cx: f64, cy: f64, r0: f64, r1: f64) -> Vec<Option<[f64; 3]>> {
+    // 半径都经过check_radius, 不会是inf或者特别大的数
+    let steps = if r1 >= r0 { (r1 - r0).floor() as usize + 1 } else { 0 };
+    (0..ANGLE_BINS).map(|bin| {
+        let theta = bin as f64 * 2.0 * PI / ANGLE_BINS as f64;
+        let (cos, sin) = (theta.cos(), theta.sin());
+        let mut total = [0.0; 3];
+        let mut count = 0;
+        for k in 0..steps {
+            let r = r0 + k as f64;
+            if let Some(rgb) = sample(img, cx + r * cos, cy + r * sin) {
+                for c in 0..3 {
+                    total[c] += rgb[c];
+                }
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return None;
+        }
+        Some(total.map(|val| val / count as f64))
+    }).collect()
+}
+
+/// 采样环的厚度, 半径越大可以取得越厚
+fn ring_width(radius: f64) -> f64 {
+    (radius * 0.08).clamp(2.0, 6.0)
+}
+
+/// 内圈顺时针转`shift`个角度之后和外圈的相关系数, 三个通道分别去均值求相关再平均
+fn correlation(inner: &[Option<[f64; 3]>], outer: &[Option<[f64; 3]>], shift: usize) -> f64 {
+    // 顺时针转shift度之后, 内圈theta - shift位置的像素到了theta
+    let pairs: Vec<([f64; 3], [f64; 3])> = (0..ANGLE_BINS)
+        .filter_map(|theta| {
+            let source = (theta + ANGLE_BINS - shift) % ANGLE_BINS;
+            Some((inner[source]?, outer[theta]?))
+        })
+        .collect();
+    if pairs.len() < ANGLE_BINS / 4 {
+        return -1.0;
+    }
+    let n = pairs.len() as f64;
+    let mut correlation = 0.0;
+    for c in 0..3 {
+        let mean_a = pairs.iter().map(|p| p.0[c]).sum::<f64>() / n;
+        let mean_b = pairs.iter().map(|p| p.1[c]).sum::<f64>() / n;
+        let (mut cross, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+        for (a, b) in &pairs {
+            cross += (a[c] - mean_a) * (b[c] - mean_b);
+            var_a += (a[c] - mean_a).powi(2);
+            var_b += (b[c] - mean_b).powi(2);
+        }
+        if var_a > 0.0 && var_b > 0.0 {
+            correlation += cross / (var_a * var_b).sqrt();
+        }
+    }
+    correlation / 3.0
+}
+
+/// 半径必须是有限的数, 内圈采样环不能越过圆心, 也不能超出图片
+fn check_radius(radius: f64, half_extent: f64) -> Result<()> {
+    if !radius.is_finite() || radius <= BORDER_GAP + ring_width(radius) || radius > half_extent {
+        return Err(Error::InvalidParameter(format!(
+            "radius {} out of range ({}, {}]", radius, BORDER_GAP + ring_width(radius), half_extent)));
+    }
+    Ok(())
+}
+
+/// 内外两圈的循环互相关: 对每个旋转角度算一次去均值之后的相关系数, 取最大的那个,
+/// 再用相邻两个角度做抛物线插值得到小数角度
+fn best_rotation(inner: &[Option<[f64; 3]>], outer: &[Option<[f64; 3]>]) -> Result<(f64, f64)> {
+    if inner.iter().flatten().count() < ANGLE_BINS / 4 || outer.iter().flatten().count() < ANGLE_BINS / 4 {
+        return Err(Error::InvalidParameter("not enough pixels on the circle border, check the radius".to_string()));
+    }
+
+    let scores: Vec<f64> = (0..ANGLE_BINS).map(|shift| correlation(inner, outer, shift)).collect();
+
+    let (best, &score) = scores.iter().enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .unwrap();
+    let prev = scores[(best + ANGLE_BINS - 1) % ANGLE_BINS];
+    let next = scores[(best + 1) % ANGLE_BINS];
+    let denominator = prev - 2.0 * score + next;
+    let offset = if denominator < 0.0 { (0.5 * (prev - next) / denominator).clamp(-0.5, 0.5) } else { 0.0 };
+    let step = 360.0 / ANGLE_BINS as f64;
+    let angle = ((best as f64 + offset) * step).rem_euclid(360.0);
+    Ok((angle, score.clamp(0.0, 1.0)))
+}
+
+/// 合成图里找内圈半径: 圆心固定在图片中心。
+/// 正常的图片相邻两圈像素高度相关, 内圈转过之后边界两侧就对不上了, 取两侧相关性最低的半径
+fn estimate_radius(img: &RgbaImage) -> Result<f64> {
+    let (cx, cy) = (img.width() as f64 / 2.0, img.height() as f64 / 2.0);
+    let max_radius = cx.min(cy) - BORDER_GAP - 2.0;
+    let min_radius = (cx.min(cy) / 4.0).max(8.0);
+    if max_radius <= min_radius {
+        return Err(Error::InvalidParameter("image is too small to estimate the rotation radius".to_string()));
+    }
+    let mut best = (min_radius, f64::MAX);
+    for k in 0..=(max_radius - min_radius).floor() as usize {
+        let r = min_radius + k as f64;
+        let inner = radial_profile(img, cx, cy, r - BORDER_GAP, r - 1.0);
+        let outer = radial_profile(img, cx, cy, r + 1.0, r + BORDER_GAP);
+        let continuity = correlation(&inner, &outer, 0);
+        if continuity < best.1 {
+            best = (r, continuity);
+        }
+    }
+    Ok(best.0)
+}
+
+/// 内圈和外圈画在同一张图上, 圆心在图片中心。
+/// `radius`不传的话按边界两侧的连续性自动估计, 内圈刚好没转的时候估计不准, 最好传进来
+pub fn rotation_from_composite(image: &DynamicImage, radius: Option<f64>) -> Result<RotationMatch> {
+    let img = image.to_rgba8();
+    let radius = match radius {
+        Some(radius) => radius,
+        None => estimate_radius(&img)?,
+    };
+    let (cx, cy) = (img.width() as f64 / 2.0, img.height() as f64 / 2.0);
+    check_radius(radius, cx.min(cy))?;
+    let width = ring_width(radius);
+    let inner = radial_profile(&img, cx, cy, radius - BORDER_GAP - width, radius - BORDER_GAP);
+    let outer = radial_profile(&img, cx, cy, radius + BORDER_GAP, radius + BORDER_GAP + width);
+    let (angle, score) = best_rotation(&inner, &outer)?;
+    Ok(RotationMatch { angle, score, radius })
+}
+
+/// 内圈(圆盘, 圆外透明)和外圈背景分开给的情况, 圆盘放在背景图的中心。
+/// `radius`不传的话取圆盘图片短边的一半
+pub fn rotation_from_parts(disc: &DynamicImage, background: &DynamicImage, radius: Option<f64>) -> Result<RotationMatch> {
+    let disc = disc.to_rgba8();
+    let background = background.to_rgba8();
+    let radius = radius.unwrap_or(disc.width().min(disc.height()) as f64 / 2.0);
+    let (dx, dy) = (disc.width() as f64 / 2.0, disc.height() as f64 / 2.0);
+    let (bx, by) = (background.width() as f64 / 2.0, background.height() as f64 / 2.0);
+    check_radius(radius, dx.min(dy).min(bx).min(by))?;
+    let width = ring_width(radius);
+    let inner = radial_profile(&disc, dx, dy, radius - BORDER_GAP - width, radius - BORDER_GAP);
+    let outer = radial_profile(&background, bx, by, radius + BORDER_GAP, radius + BORDER_GAP + width);
+    let (angle, score) = best_rotation(&inner, &outer)?;
+    Ok(RotationMatch { angle, score, radius })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::rotation::{rotation_from_composite, rotation_from_parts};
+    use image::{DynamicImage, GenericImage, Rgba};
+    use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+
+    const RADIUS: f64 = 50.0;
+
+    /// 从测试图里取一块正方形, 中间半径50的圆盘逆时针转`angle`度, 返回(合成图, 圆盘, 挖掉圆盘的背景)
+    fn make_captcha(angle: f64) -> (DynamicImage, DynamicImage, DynamicImage) {
+        let source = image::open("./src/images/0.jpg").unwrap().crop_imm(60, 0, 150, 150).to_rgba8();
+        let rotated = rotate_about_center(&source, (-angle).to_radians() as f32, Interpolation::Bilinear, Rgba([0, 0, 0, 0]));
+        let mut composite = DynamicImage::ImageRgba8(source.clone());
+        let mut disc = DynamicImage::new_rgba8(100, 100);
+        let mut background = DynamicImage::ImageRgba8(source);
+        for y in 0..150u32 {
+            for x in 0..150u32 {
+                let (dx, dy) = (x as f64 + 0.5 - 75.0, y as f64 + 0.5 - 75.0);
+                if dx * dx + dy * dy <= RADIUS * RADIUS {
+                    let pixel = *rotated.get_pixel(x, y);
+                    composite.put_pixel(x, y, pixel);
+                    disc.put_pixel(x - 25, y - 25, pixel);
+                    background.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+                }
+            }
+        }
+        (composite, disc, background)
+    }
+
+    fn angle_error(actual: f64, expected: f64) -> f64 {
+        let diff = (actual - expected).rem_euclid(360.0);
+        diff.min(360.0 - diff)
+    }
+
+    #[test]
+    fn test_rotation_from_composite() {
+        for rotated in [0.0, 37.0, 145.0, 290.0] {
+            // 逆时针转了多少度, 就要顺时针转回来多少度
+            let (composite, _, _) = make_captcha(rotated);
+            let result = rotation_from_composite(&composite, Some(RADIUS)).unwrap();
+            assert!(angle_error(result.angle, rotated) <= 2.0, "{} {:?}", rotated, result);
+            assert!(result.score > 0.5, "{:?}", result);
+
+            // 没有转的话边界两侧是连续的, 没法估计半径
+            if rotated == 0.0 {
+                continue;
+            }
+            let estimated = rotation_from_composite(&composite, None).unwrap();
+            assert!((estimated.radius - RADIUS).abs() <= 3.0, "{:?}", estimated);
+            assert!(angle_error(estimated.angle, rotated) <= 3.0, "{} {:?}", rotated, estimated);
+        }
+    }
+
+    #[test]
+    fn test_rotation_from_parts() {
+        let (_, disc, background) = make_captcha(100.0);
+        let result = rotation_from_parts(&disc, &background, None).unwrap();
+        assert!(angle_error(result.angle, 100.0) <= 2.0, "{:?}", result);
+        assert_eq!(result.radius, RADIUS);
+
+        // 半径太大, 圆盘上采样不到像素
+        let err = rotation_from_parts(&disc, &background, Some(90.0)).unwrap_err();
+        assert!(matches!(err, Error::InvalidParameter(_)));
+    }
+
+    #[test]
+    fn test_invalid_radius() {
+        let (composite, disc, background) = make_captcha(30.0);
+        for radius in [f64::INFINITY, f64::NAN, 1e300, -5.0, 0.0, 4.0, 80.0] {
+            let err = rotation_from_composite(&composite, Some(radius)).unwrap_err();
+            assert!(matches!(err, Error::InvalidParameter(_)), "{}", radius);
+            let err = rotation_from_parts(&disc, &background, Some(radius)).unwrap_err();
+            assert!(matches!(err, Error::InvalidParameter(_)), "{}", radius);
+        }
+        // 空图片
+        let empty = DynamicImage::new_rgba8(0, 0);
+        assert!(rotation_from_composite(&empty, Some(RADIUS)).is_err());
+        assert!(rotation_from_parts(&empty, &background, None).is_err());
+    }
+}
@@ -0,0 +1,349 @@
+use std::cmp::min;
+
+use image::{DynamicImage, GenericImageView, GrayImage, Luma};
+use imageproc::distance_transform::Norm;
+use imageproc::gradients::sobel_gradients;
+use imageproc::morphology::erode;
+
+use crate::error::{Error, Result};
+use crate::image_hill_top_v2::{find_peaks, PeakOptions};
+use crate::image_utils::{Grid, IntegralImage, MAX_RGB_DIFF};
+
+/// alpha大于这个值的像素算拼图块本体
+const ALPHA_THRESHOLD: u8 = 127;
+
+/// 滑块缺口的匹配结果, `x`/`y`是拼图块图片左上角在底图里应该放的位置
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SliderMatch {
+    pub x: usize,
+    pub y: usize,
+    /// 归一化互相关系数, 0..1, 越大越可信
+    pub score: f64,
+}
+
+/// 拼图块的边缘模板: 轮廓边缘(alpha通道的梯度)加上内部纹理边缘
+struct Template {
+    /// 拼图块非透明区域的外接矩形左上角, 模板只保留这一块
+    left: usize,
+    top: usize,
+    /// 已经减去均值的边缘强度
+    data: Grid<f64>,
+    /// `data`的平方和开根号
+    norm: f64,
+}
+
+impl Template {
+    fn new(piece: &DynamicImage) -> Result<Template> {
+        let rgba = piece.to_rgba8();
+        let (width, height) = rgba.dimensions();
+        let alpha = GrayImage::from_fn(width, height, |x, y| {
+            Luma([if rgba.get_pixel(x, y)[3] > ALPHA_THRESHOLD { 255 } else { 0 }])
+        });
+
+        // 非透明区域的外接矩形
+        let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+        for (x, y, pixel) in alpha.enumerate_pixels() {
+            if pixel[0] > 0 {
+                left = left.min(x);
+                top = top.min(y);
+                right = right.max(x);
+                bottom = bottom.max(y);
+            }
+        }
+        if left > right {
+            return Err(Error::EmptyInput("slider piece is fully transparent".to_string()));
+        }
+
+        let outline = sobel_gradients(&alpha);
+        let texture = sobel_gradients(&piece.to_luma8());
+        // 轮廓附近的纹理边缘被透明像素污染了, 只保留腐蚀之后的内部
+        let interior = erode(&alpha, Norm::LInf, 1);
+
+        let (w, h) = ((right - left + 1) as usize, (bottom - top + 1) as usize);
+        let mut data = Grid::from_fn(w, h, |x, y| {
+            let (px, py) = (x as u32 + left, y as u32 + top);
+            let mut edge = outline.get_pixel(px, py)[0] as f64;
+            if interior.get_pixel(px, py)[0] > 0 {
+                edge += texture.get_pixel(px, py)[0] as f64;
+            }
+            edge
+        });
+
+        let mean = (0..h).map(|y| data.row(y).iter().sum::<f64>()).sum::<f64>() / (w * h) as f64;
+        let mut norm = 0.0;
+        for y in 0..h {
+            for x in 0..w {
+                data[(x, y)] -= mean;
+                norm += data[(x, y)] * data[(x, y)];
+            }
+        }
+        Ok(Template { left: left as usize, top: top as usize, data, norm: norm.sqrt() })
+    }
+}
+
+/// 在带缺口的底图里找拼图块的位置。
+///
+/// 两张图都先用Sobel算子求边缘强度, 拼图块只取非透明部分(轮廓加内部纹理),
+/// 然后在底图的边缘图上逐位置算归一化互相关, 取最大值。
+/// 窗口内的均值和方差用积分图计算, 开启`parallel`特性时各个位置并行计算。
+pub fn slider_offset(bg_image: &DynamicImage, piece_image: &DynamicImage) -> Result<SliderMatch> {
+    let template = Template::new(piece_image)?;
+    let (tw, th) = (template.data.width(), template.data.height());
+    let (width, height) = (bg_image.width() as usize, bg_image.height() as usize);
+    if tw > width || th > height {
+        return Err(Error::SizeMismatch {
+            expected: (tw as u32, th as u32),
+            actual: bg_image.dimensions(),
+        });
+    }
+
+    let edges = sobel_gradients(&bg_image.to_luma8());
+    let edges = Grid::from_fn(width, height, |x, y| edges.get_pixel(x as u32, y as u32)[0] as u64);
+    let squares = Grid::from_fn(width, height, |x, y| edges[(x, y)] * edges[(x, y)]);
+    let sum = IntegralImage::new(&edges);
+    let square_sum = IntegralImage::new(&squares);
+    let count = (tw * th) as f64;
+
+    let scores = Grid::from_fn(width - tw + 1, height - th + 1, |x, y| {
+        let window_sum = sum.sum(x, y, x + tw - 1, y + th - 1) as f64;
+        let window_square_sum = square_sum.sum(x, y, x + tw - 1, y + th - 1) as f64;
+        let variance = window_square_sum - window_sum * window_sum / count;
+        if variance <= 0.0 || template.norm == 0.0 {
+            return 0.0;
+        }
+        // 模板已经去过均值, 和窗口原始值的点积就等于去均值之后的点积
+        let mut cross = 0.0;
+        for ty in 0..th {
+            let row = &edges.row(y + ty)[x..x + tw];
+            for (t, val) in template.data.row(ty).iter().zip(row) {
+                cross += t * *val as f64;
+            }
+        }
+        cross / (template.norm * variance.sqrt())
+    });
+
+    let mut best = SliderMatch { x: 0, y: 0, score: f64::MIN };
+    for y in 0..scores.height() {
+        for x in 0..scores.width() {
+            if scores[(x, y)] > best.score {
+                best = SliderMatch { x, y, score: scores[(x, y)] };
+            }
+        }
+    }
+    // 换算回拼图块图片左上角的位置, 拼图块图片本身可能带透明边
+    Ok(SliderMatch {
+        x: best.x.saturating_sub(template.left),
+        y: best.y.saturating_sub(template.top),
+        score: best.score.clamp(0.0, 1.0),
+    })
+}
+
+/// 只有带缺口的底图、没有拼图块时的检测参数
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ShadowOptions {
+    /// 缺口的大概边长
+    pub size: usize,
+    /// 缺口所在的纵向范围`[top, bottom]`, 不传就是整张图
+    pub band: Option<(usize, usize)>,
+    /// 最多返回几个候选位置
+    pub top_n: usize,
+}
+
+impl ShadowOptions {
+    pub fn new(size: usize) -> ShadowOptions {
+        ShadowOptions { size, band: None, top_n: 3 }
+    }
+
+    pub fn with_band(mut self, top: usize, bottom: usize) -> ShadowOptions {
+        self.band = Some((top, bottom));
+        self
+    }
+
+    pub fn with_top_n(mut self, top_n: usize) -> ShadowOptions {
+        self.top_n = top_n;
+        self
+    }
+}
+
+/// 阴影检测出来的候选缺口, `x`/`y`是缺口左上角
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GapCandidate {
+    pub x: usize,
+    pub y: usize,
+    /// 相对于整张图里最像缺口的位置的得分, 0..1
+    pub score: f64,
+}
+
+/// 只靠阴影和轮廓找滑块缺口。
+///
+/// 对每个可能的缺口中心, 用积分图算出两个量:
+/// 方框内的平均亮度比外面一圈暗多少(阴影), 方框边界上的平均边缘强度(轮廓)。
+/// 两者相乘得到得分图, 再交给`image_hill_top_v2`的山峰搜索找出最突出的几个位置,
+/// 山峰是平滑之后的结果, 最后在附近`margin`范围里对齐到得分图的最大值。
+pub fn find_gap_by_shadow(bg_image: &DynamicImage, options: &ShadowOptions) -> Result<Vec<GapCandidate>> {
+    let (width, height) = (bg_image.width() as usize, bg_image.height() as usize);
+    if options.size < 4 {
+        return Err(Error::InvalidParameter("gap size must be at least 4".to_string()));
+    }
+    let (band_top, band_bottom) = options.band.unwrap_or((0, height.saturating_sub(1)));
+    if band_top > band_bottom || band_bottom >= height {
+        return Err(Error::InvalidParameter(format!("band ({}, {}) is outside the image", band_top, band_bottom)));
+    }
+
+    let luma = bg_image.to_luma8();
+    let edges = sobel_gradients(&luma);
+    let luma = Grid::from_fn(width, height, |x, y| luma.get_pixel(x as u32, y as u32)[0] as u64);
+    let edges = Grid::from_fn(width, height, |x, y| edges.get_pixel(x as u32, y as u32)[0] as u64);
+    let luma_sum = IntegralImage::new(&luma);
+    let edge_sum = IntegralImage::new(&edges);
+
+    let half = options.size / 2;
+    // 外圈宽度和轮廓的容差
+    let margin = (options.size / 8).max(2);
+    let border = (options.size / 10).max(1);
+    let reach = half + margin.max(border);
+    let area = |r: usize| ((2 * r + 1) * (2 * r + 1)) as f64;
+    let box_sum = |table: &IntegralImage, cx: usize, cy: usize, r: usize| {
+        table.sum(cx - r, cy - r, cx + r, cy + r) as f64
+    };
+
+    let raw = Grid::from_fn(width, height, |cx, cy| {
+        // 方框加上外圈都要在图里, 方框要在指定的纵向范围里
+        if cx < reach || cy < reach || cx + reach >= width || cy + reach >= height
+            || cy - half < band_top || cy + half > band_bottom {
+            return 0.0;
+        }
+        let inside = box_sum(&luma_sum, cx, cy, half);
+        let outside = box_sum(&luma_sum, cx, cy, half + margin) - inside;
+        let darkness = outside / (area(half + margin) - area(half)) - inside / area(half);
+
+        let outer = box_sum(&edge_sum, cx, cy, half + border);
+        let inner = box_sum(&edge_sum, cx, cy, half - border);
+        let outline = (outer - inner) / (area(half + border) - area(half - border));
+        darkness.max(0.0) * outline
+    });
+
+    let max_score = (0..height).flat_map(|y| raw.row(y).iter().copied()).fold(0.0, f64::max);
+    if max_score <= 0.0 {
+        return Ok(vec![]);
+    }
+    // 缩放到和rgb_diff一样的量级再去找山峰
+    let scores = Grid::from_fn(width, height, |x, y| (raw[(x, y)] / max_score * MAX_RGB_DIFF as f64) as u64);
+
+    let mut candidates: Vec<GapCandidate> = vec![];
+    for point in find_peaks(scores, options.size as u32, options.top_n, &PeakOptions::default(), None) {
+        let (mut cx, mut cy) = (point.x, point.y);
+        for y in point.y.saturating_sub(margin)..=min(point.y + margin, height - 1) {
+            for x in point.x.saturating_sub(margin)..=min(point.x + margin, width - 1) {
+                if raw[(x, y)] > raw[(cx, cy)] {
+                    (cx, cy) = (x, y);
+                }
+            }
+        }
+        if raw[(cx, cy)] <= 0.0 || candidates.iter().any(|c| (c.x, c.y) == (cx - half, cy - half)) {
+            continue;
+        }
+        candidates.push(GapCandidate { x: cx - half, y: cy - half, score: raw[(cx, cy)] / max_score });
+    }
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::slider::{find_gap_by_shadow, slider_offset, ShadowOptions};
+    use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+
+    /// 从底图(x0, y0)处挖一个带凸起的方块, 返回(带缺口的底图, 拼图块)
+    fn make_puzzle(x0: u32, y0: u32, padding: u32) -> (DynamicImage, DynamicImage) {
+        let source = image::open("./src/images/0.jpg").unwrap();
+        let size = 40;
+        let inside = |x: u32, y: u32| {
+            let in_square = (6..size - 6).contains(&x) && (6..size - 6).contains(&y);
+            let (dx, dy) = (x as i32 - size as i32 / 2, y as i32 - 6);
+            in_square || dx * dx + dy * dy <= 36
+        };
+        let mut bg = source.clone();
+        let mut piece = DynamicImage::new_rgba8(size + padding * 2, size + padding * 2);
+        for y in 0..size {
+            for x in 0..size {
+                if !inside(x, y) {
+                    continue;
+                }
+                let pixel = source.get_pixel(x0 + x, y0 + y);
+                piece.put_pixel(x + padding, y + padding, pixel);
+                // 缺口变暗
+                let dark = Rgba([pixel[0] / 3, pixel[1] / 3, pixel[2] / 3, 255]);
+                bg.put_pixel(x0 + x, y0 + y, dark);
+            }
+        }
+        (bg, piece)
+    }
+
+    #[test]
+    fn test_slider_offset() {
+        for (x0, y0) in [(180, 60), (40, 90), (250, 20)] {
+            let (bg, piece) = make_puzzle(x0, y0, 0);
+            let result = slider_offset(&bg, &piece).unwrap();
+            assert!(result.x.abs_diff(x0 as usize) <= 1 && result.y.abs_diff(y0 as usize) <= 1, "{:?}", result);
+            assert!(result.score > 0.5, "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_slider_offset_with_padding() {
+        // 拼图块图片带透明边, 返回的是图片左上角的位置
+        let (bg, piece) = make_puzzle(150, 70, 5);
+        let result = slider_offset(&bg, &piece).unwrap();
+        assert!(result.x.abs_diff(145) <= 1 && result.y.abs_diff(65) <= 1, "{:?}", result);
+    }
+
+    #[test]
+    fn test_slider_invalid_input() {
+        let (bg, _) = make_puzzle(150, 70, 0);
+        let empty = DynamicImage::new_rgba8(30, 30);
+        assert!(matches!(slider_offset(&bg, &empty), Err(Error::EmptyInput(_))));
+        let mut large = DynamicImage::new_rgba8(400, 20);
+        large.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        large.put_pixel(399, 0, Rgba([255, 255, 255, 255]));
+        assert!(matches!(slider_offset(&bg, &large), Err(Error::SizeMismatch { .. })));
+    }
+
+    /// 把(x0, y0)处边长`size`的方块压暗, 模拟只有阴影的缺口
+    fn darken(img: &mut DynamicImage, x0: u32, y0: u32, size: u32) {
+        for y in y0..y0 + size {
+            for x in x0..x0 + size {
+                let pixel = img.get_pixel(x, y);
+                img.put_pixel(x, y, Rgba([pixel[0] / 3, pixel[1] / 3, pixel[2] / 3, 255]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_gap_by_shadow() {
+        let mut bg = image::open("./src/images/0.jpg").unwrap();
+        darken(&mut bg, 190, 70, 36);
+        let result = find_gap_by_shadow(&bg, &ShadowOptions::new(36)).unwrap();
+        assert!(!result.is_empty());
+        assert!(result[0].x.abs_diff(190) <= 2 && result[0].y.abs_diff(70) <= 2, "{:?}", result);
+        assert!((result[0].score - 1.0).abs() < 1e-9);
+        assert!(result.windows(2).all(|pair| pair[0].score >= pair[1].score));
+    }
+
+    #[test]
+    fn test_find_gap_by_shadow_band() {
+        // 纵向范围外面还有一个更暗的干扰块
+        let mut bg = image::open("./src/images/0.jpg").unwrap();
+        darken(&mut bg, 120, 80, 36);
+        darken(&mut bg, 40, 5, 36);
+        darken(&mut bg, 40, 5, 36);
+        let options = ShadowOptions::new(36).with_band(60, 140).with_top_n(2);
+        let result = find_gap_by_shadow(&bg, &options).unwrap();
+        assert!(result[0].x.abs_diff(120) <= 2 && result[0].y.abs_diff(80) <= 2, "{:?}", result);
+        assert!(result.iter().all(|c| c.y >= 60 && c.y + 36 <= 141), "{:?}", result);
+
+        assert!(matches!(find_gap_by_shadow(&bg, &ShadowOptions::new(36).with_band(100, 500)), Err(Error::InvalidParameter(_))));
+        assert!(matches!(find_gap_by_shadow(&bg, &ShadowOptions::new(2)), Err(Error::InvalidParameter(_))));
+    }
+}
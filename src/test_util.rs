@@ -0,0 +1,14 @@
+//! 各个模块的测试共用的样本图片和临时目录
+
+use image::DynamicImage;
+use tempfile::TempDir;
+
+/// `src/images`下同一张底图的4张挑战图
+pub(crate) fn load_images() -> Vec<DynamicImage> {
+    (0..4).map(|i| image::open(format!("./src/images/{}.jpg", i)).unwrap()).collect()
+}
+
+/// 每次调用都是一个新的空目录, 并行跑的测试之间不会互相覆盖, drop的时候删除
+pub(crate) fn temp_dir() -> TempDir {
+    tempfile::Builder::new().prefix("image_magic_").tempdir().unwrap()
+}
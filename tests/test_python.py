@@ -0,0 +1,61 @@
+"""Python层的接口测试, 先`maturin develop`装好扩展模块, 再`python -m unittest discover tests`"""
+import base64
+import os
+import struct
+import tempfile
+import unittest
+
+import image_magic
+
+IMAGES = os.path.join(os.path.dirname(__file__), "..", "src", "images")
+
+
+def load_b64(i):
+    with open(os.path.join(IMAGES, "{}.jpg".format(i)), "rb") as f:
+        return base64.b64encode(f.read()).decode()
+
+
+class TopNTest(unittest.TestCase):
+    def test_positional_arguments(self):
+        # 最早的调用方式, 四个参数都按位置传
+        bg_image = image_magic.avg_b64([load_b64(i) for i in range(4)])
+        points = image_magic.top_n(bg_image, load_b64(0), 40, 3)
+        self.assertEqual(len(points), 3)
+        self.assertEqual([p.rank for p in points], [1, 2, 3])
+
+    def test_async_positional_arguments(self):
+        bg_image = image_magic.avg_b64([load_b64(i) for i in range(4)])
+        points = image_magic.top_n_async(bg_image, load_b64(0), 40, 3).result()
+        self.assertEqual(len(points), 3)
+
+    def test_options_are_keyword_only(self):
+        with self.assertRaises(TypeError):
+            image_magic.top_n(load_b64(1), load_b64(0), 40, 3, "resize")
+
+    def test_missing_file(self):
+        with self.assertRaises(FileNotFoundError) as ctx:
+            image_magic.top_n("./missing/bg.png", load_b64(0), 40, 3)
+        self.assertIn("bg.png", str(ctx.exception))
+
+    def test_no_background(self):
+        with self.assertRaises(image_magic.InvalidParameterError):
+            image_magic.top_n(None, load_b64(0), 40, 3)
+
+
+class BackgroundAccumulatorTest(unittest.TestCase):
+    def test_corrupted_pixel_count(self):
+        acc = image_magic.BackgroundAccumulator(warmup=0)
+        acc.add(load_b64(0))
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "acc.bin")
+            acc.save(path)
+            with open(path, "r+b") as f:
+                # 头部38字节之后是第一个像素, 第9个u64是样本数
+                f.seek(38 + 8 * 8)
+                f.write(struct.pack("<Q", 0))
+            with self.assertRaises(image_magic.CorruptedError):
+                image_magic.BackgroundAccumulator.load(path)
+
+
+if __name__ == "__main__":
+    unittest.main()